@@ -0,0 +1,87 @@
+//! Builds a binary-shaped tree of `ChildrenNode`s and reports the time, heap allocations and
+//! heap bytes it took. Compare the inline child storage against plain `Vec`s with
+//! `cargo bench --bench build_tree` and `cargo bench --bench build_tree --no-default-features`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tree_hashmap::tree::tree::{Node, NodeKind, NodeTree};
+
+const FAN_OUT: usize = 2;
+const NODES: usize = 1_000_000;
+const ROUNDS: usize = 5;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    System.dealloc(ptr, layout)
+  }
+
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    LIVE_BYTES.fetch_add(new_size, Ordering::Relaxed);
+    LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+    System.realloc(ptr, layout, new_size)
+  }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn build_tree() -> NodeTree {
+  let mut node_tree = NodeTree::new();
+  let mut level = vec![node_tree.make_root()];
+  let mut count = 1;
+  while count < NODES {
+    let mut next_level = Vec::with_capacity(level.len() * FAN_OUT);
+    'level: for parent_id in level {
+      for _ in 0..FAN_OUT {
+        if count == NODES {
+          break 'level;
+        }
+        next_level.push(node_tree.add_child(parent_id, NodeKind::Children).unwrap());
+        count += 1;
+      }
+    }
+    level = next_level;
+  }
+  node_tree
+}
+
+fn main() {
+  let mut best = Duration::MAX;
+  let mut allocations = 0;
+  let mut bytes = 0;
+  for _ in 0..ROUNDS {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes_before = LIVE_BYTES.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let node_tree = black_box(build_tree());
+    best = best.min(start.elapsed());
+    allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    bytes = LIVE_BYTES.load(Ordering::Relaxed) - bytes_before;
+    drop(node_tree);
+  }
+  println!(
+    "{} nodes, fan-out {}: best of {} rounds {:?}, {} allocations, {} MiB held, {}-byte nodes",
+    NODES,
+    FAN_OUT,
+    ROUNDS,
+    best,
+    allocations,
+    bytes >> 20,
+    std::mem::size_of::<Node>()
+  );
+}
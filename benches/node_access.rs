@@ -0,0 +1,90 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use tree_hashmap::tree::tree::{ChildrenNode, Node, NodeTree};
+use uuid::Uuid;
+
+const FAN_OUT: usize = 4;
+const DEPTH: usize = 8;
+const ROUNDS: usize = 20;
+// Small enough to stay in cache, so the timed loop is bound by the accessor rather than memory.
+const HOT_NODES: usize = 4096;
+const PASSES: usize = 200;
+
+fn build_tree() -> (NodeTree, Uuid) {
+  let mut node_tree = NodeTree::new();
+  let root_id = node_tree.make_root();
+  let mut level = vec![root_id];
+  for _ in 0..DEPTH {
+    let mut next_level = Vec::with_capacity(level.len() * FAN_OUT);
+    for parent_id in level {
+      for _ in 0..FAN_OUT {
+        let id = node_tree
+          .make_node(
+            parent_id,
+            Box::new(|id, parent| {
+              let parent_inner = parent.as_children_mut().unwrap();
+              parent_inner.push_child(id);
+              ChildrenNode::new(id, parent_inner.id)
+            }),
+          )
+          .unwrap();
+        next_level.push(id);
+      }
+    }
+    level = next_level;
+  }
+  (node_tree, root_id)
+}
+
+// Nodes are fetched up front so the timed loop measures only the accessor dispatch, not the
+// HashMap lookups of a real traversal.
+fn collect_nodes(node_tree: &NodeTree, root_id: Uuid) -> Vec<&Node> {
+  let mut nodes = Vec::new();
+  let mut stack = vec![root_id];
+  while nodes.len() < HOT_NODES {
+    let id = match stack.pop() {
+      Some(id) => id,
+      None => break,
+    };
+    let node = node_tree.get_node(id).unwrap();
+    stack.extend(node.children());
+    nodes.push(node);
+  }
+  nodes
+}
+
+fn count_children(nodes: &[&Node], inner: fn(&Node) -> &ChildrenNode) -> usize {
+  let mut total = 0;
+  for _ in 0..PASSES {
+    for node in nodes {
+      total += inner(black_box(node)).children_slice().len();
+    }
+  }
+  total
+}
+
+#[allow(deprecated)]
+fn via_any(node: &Node) -> &ChildrenNode {
+  node.get_inner::<ChildrenNode>().unwrap()
+}
+
+fn via_match(node: &Node) -> &ChildrenNode {
+  node.as_children().unwrap()
+}
+
+fn measure(name: &str, nodes: &[&Node], inner: fn(&Node) -> &ChildrenNode) {
+  let mut best = Duration::MAX;
+  for _ in 0..ROUNDS {
+    let start = Instant::now();
+    black_box(count_children(nodes, black_box(inner)));
+    best = best.min(start.elapsed());
+  }
+  println!("{:<12} best of {} rounds: {:?}", name, ROUNDS, best);
+}
+
+fn main() {
+  let (node_tree, root_id) = build_tree();
+  let nodes = collect_nodes(&node_tree, root_id);
+  measure("get_inner", &nodes, via_any);
+  measure("as_children", &nodes, via_match);
+}
@@ -0,0 +1,61 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use tree_hashmap::tree::tree::{Node, NodeTree};
+use uuid::Uuid;
+
+const DEPTH: usize = 6;
+const BRANCHING: usize = 8;
+const ROUNDS: usize = 5;
+const WORK: u32 = 200;
+
+// Stand-in for expensive per-node work: hash the id over and over.
+fn work(id: Uuid, node: &Node) -> u64 {
+  let mut acc = node.child_count() as u64;
+  for round in 0..WORK {
+    for byte in id.as_bytes() {
+      acc = acc
+        .wrapping_mul(31)
+        .wrapping_add(u64::from(*byte) ^ u64::from(round));
+    }
+  }
+  acc
+}
+
+fn measure<F: Fn()>(name: &str, f: F) {
+  let mut best = Duration::MAX;
+  for _ in 0..ROUNDS {
+    let start = Instant::now();
+    f();
+    best = best.min(start.elapsed());
+  }
+  println!("{:<14} best of {} rounds: {:?}", name, ROUNDS, best);
+}
+
+#[cfg(feature = "rayon")]
+fn measure_parallel(node_tree: &NodeTree) {
+  use std::sync::atomic::{AtomicU64, Ordering};
+
+  measure("par_for_each", || {
+    let total = AtomicU64::new(0);
+    node_tree.par_for_each(|id, node| {
+      total.fetch_add(work(id, node), Ordering::Relaxed);
+    });
+    black_box(total.into_inner());
+  });
+}
+
+#[cfg(not(feature = "rayon"))]
+fn measure_parallel(_node_tree: &NodeTree) {
+  println!("par_for_each   skipped, run with `--features rayon`");
+}
+
+fn main() {
+  let (node_tree, _) = NodeTree::gen_balanced(DEPTH, BRANCHING);
+  println!("{} nodes", node_tree.iter().count());
+  measure("for_each", || {
+    let mut total = 0u64;
+    node_tree.for_each(|id, node| total = total.wrapping_add(work(id, node)));
+    black_box(total);
+  });
+  measure_parallel(&node_tree);
+}
@@ -1,5 +1,3 @@
-mod tree;
-
 fn main() {
   println!("Hello, world!");
 }
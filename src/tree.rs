@@ -1,41 +1,190 @@
+#[allow(clippy::module_inception)]
 pub mod tree {
-  use std::any::Any;
-  use std::cmp::PartialEq;
-  use std::collections::HashMap;
+  use std::any::{Any, TypeId};
+  use std::collections::{HashMap, HashSet, VecDeque};
+  use std::fmt;
   use std::hash::{Hash, Hasher};
   use uuid::Uuid;
 
-  #[derive(Debug)]
-  pub struct ChildrenNode {
-    pub id: Uuid,
-    pub parent: Uuid,
-    pub children: Vec<Uuid>,
+  /// Requirements for the key type of a `GenericTree`.
+  pub trait Key: Eq + Hash + Clone {}
+
+  impl<K: Eq + Hash + Clone> Key for K {}
+
+  /// Id, parent and child access shared by every node kind, for algorithms that should not care
+  /// about the concrete variant.
+  pub trait TreeNode<K = Uuid> {
+    fn id(&self) -> K;
+    fn parent(&self) -> K;
+    fn child_ids(&self) -> Vec<K>;
+    fn set_parent(&mut self, parent: K);
+    fn add_child_id(&mut self, id: K) -> Result<(), Error<K>>;
+    fn remove_child_id(&mut self, id: K) -> bool;
+  }
+
+  /// Storage for child ids. With the default `smallvec` feature the first `INLINE_CHILDREN`
+  /// ids live inside the node, so small nodes don't allocate.
+  #[cfg(feature = "smallvec")]
+  type ChildList<T> = smallvec::SmallVec<[T; INLINE_CHILDREN]>;
+  #[cfg(not(feature = "smallvec"))]
+  type ChildList<T> = Vec<T>;
+
+  /// How many children a `ChildrenNode` stores without a heap allocation when the `smallvec`
+  /// feature is enabled. Most nodes have 0-2 children, and every inline slot grows every `Node`.
+  pub const INLINE_CHILDREN: usize = 2;
+
+  #[derive(Debug, PartialEq, Eq)]
+  pub struct ChildrenNode<K = Uuid> {
+    pub id: K,
+    pub parent: K,
+    /// Child ids in ascending weight order.
+    children: ChildList<K>,
+    /// Weights aligned with `children`, or empty while every child's weight is its index, which
+    /// is the case unless `insert_child_weighted` was used. Kept out of line so the common case
+    /// costs no inline space or allocation.
+    weights: Vec<i64>,
   }
 
-  impl ChildrenNode {
-    pub fn new_inner(id: Uuid, parent: Uuid) -> ChildrenNode {
+  impl<K: Key> ChildrenNode<K> {
+    pub fn new_inner(id: K, parent: K) -> ChildrenNode<K> {
       ChildrenNode {
         id,
         parent,
-        children: Vec::new(),
+        children: ChildList::new(),
+        weights: Vec::new(),
+      }
+    }
+
+    /// Child ids in ascending weight order.
+    pub fn children_slice(&self) -> &[K] {
+      &self.children
+    }
+
+    /// Appends `id` with a weight one past the current maximum, which keeps insertion order.
+    pub fn push_child(&mut self, id: K) {
+      if let Some(weight) = self.weights.last() {
+        let weight = weight.saturating_add(1);
+        self.weights.push(weight);
+      }
+      self.children.push(id);
+    }
+
+    /// Inserts `id` after every child weighted less than or equal to `weight`, so ties keep
+    /// insertion order.
+    pub fn insert_child_weighted(&mut self, id: K, weight: i64) {
+      self.materialize_weights();
+      let index = self
+        .weights
+        .iter()
+        .position(|other| *other > weight)
+        .unwrap_or(self.weights.len());
+      self.children.insert(index, id);
+      self.weights.insert(index, weight);
+      self.normalize_weights();
+    }
+
+    /// Removes `id` from the children, returning whether it was there.
+    pub fn remove_child(&mut self, id: &K) -> bool {
+      match self.children.iter().position(|child_id| child_id == id) {
+        Some(index) => {
+          if index + 1 < self.children.len() {
+            self.materialize_weights();
+          }
+          self.children.remove(index);
+          if !self.weights.is_empty() {
+            self.weights.remove(index);
+            self.normalize_weights();
+          }
+          true
+        }
+        None => false,
+      }
+    }
+
+    pub fn weight_of(&self, id: K) -> Option<i64> {
+      let index = self.children.iter().position(|child_id| *child_id == id)?;
+      Some(self.weight_at(index))
+    }
+
+    pub fn weighted_children(&self) -> Vec<(K, i64)> {
+      self
+        .children
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(|(index, id)| (id, self.weight_at(index)))
+        .collect()
+    }
+
+    fn weight_at(&self, index: usize) -> i64 {
+      match self.weights.get(index) {
+        Some(weight) => *weight,
+        None => index as i64,
       }
     }
 
-    pub fn new(id: Uuid, parent: Uuid) -> Node {
+    fn materialize_weights(&mut self) {
+      if self.weights.is_empty() {
+        self.weights = (0..self.children.len() as i64).collect();
+      }
+    }
+
+    /// Drops the explicit weights again once they match the positions, so equal nodes compare
+    /// equal regardless of their history.
+    fn normalize_weights(&mut self) {
+      if self
+        .weights
+        .iter()
+        .enumerate()
+        .all(|(index, weight)| *weight == index as i64)
+      {
+        self.weights = Vec::new();
+      }
+    }
+
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(id: K, parent: K) -> Node<K> {
       Node::ChildrenNode(ChildrenNode::new_inner(id, parent))
     }
   }
 
-  #[derive(Debug)]
-  pub struct LeftRightNode {
-    pub id: Uuid,
-    pub parent: Uuid,
-    pub left: Option<Uuid>,
-    pub right: Option<Uuid>,
+  impl<K: Key> TreeNode<K> for ChildrenNode<K> {
+    fn id(&self) -> K {
+      self.id.clone()
+    }
+
+    fn parent(&self) -> K {
+      self.parent.clone()
+    }
+
+    fn child_ids(&self) -> Vec<K> {
+      self.children.to_vec()
+    }
+
+    fn set_parent(&mut self, parent: K) {
+      self.parent = parent;
+    }
+
+    fn add_child_id(&mut self, id: K) -> Result<(), Error<K>> {
+      self.push_child(id);
+      Ok(())
+    }
+
+    fn remove_child_id(&mut self, id: K) -> bool {
+      self.remove_child(&id)
+    }
+  }
+
+  #[derive(Debug, PartialEq, Eq)]
+  pub struct LeftRightNode<K = Uuid> {
+    pub id: K,
+    pub parent: K,
+    pub left: Option<K>,
+    pub right: Option<K>,
   }
 
-  impl LeftRightNode {
-    pub fn new_inner(id: Uuid, parent: Uuid) -> LeftRightNode {
+  impl<K: Key> LeftRightNode<K> {
+    pub fn new_inner(id: K, parent: K) -> LeftRightNode<K> {
       LeftRightNode {
         id,
         parent,
@@ -44,18 +193,93 @@ pub mod tree {
       }
     }
 
-    pub fn new(id: Uuid, parent: Uuid) -> Node {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(id: K, parent: K) -> Node<K> {
       Node::LeftRightNode(LeftRightNode::new_inner(id, parent))
     }
   }
 
+  impl<K: Key> TreeNode<K> for LeftRightNode<K> {
+    fn id(&self) -> K {
+      self.id.clone()
+    }
+
+    fn parent(&self) -> K {
+      self.parent.clone()
+    }
+
+    fn child_ids(&self) -> Vec<K> {
+      self.left.iter().chain(self.right.iter()).cloned().collect()
+    }
+
+    fn set_parent(&mut self, parent: K) {
+      self.parent = parent;
+    }
+
+    /// Fills the left slot first, then the right one. Fails with `Error::NoFreeSlot` when both
+    /// are taken.
+    fn add_child_id(&mut self, id: K) -> Result<(), Error<K>> {
+      if self.left.is_none() {
+        self.left = Some(id);
+        Ok(())
+      } else if self.right.is_none() {
+        self.right = Some(id);
+        Ok(())
+      } else {
+        Err(Error::NoFreeSlot)
+      }
+    }
+
+    fn remove_child_id(&mut self, id: K) -> bool {
+      if self.left.as_ref() == Some(&id) {
+        self.left = None;
+        true
+      } else if self.right.as_ref() == Some(&id) {
+        self.right = None;
+        true
+      } else {
+        false
+      }
+    }
+  }
+
   #[derive(Debug)]
-  pub enum Node {
-    ChildrenNode(ChildrenNode),
-    LeftRightNode(LeftRightNode),
+  pub enum Node<K = Uuid> {
+    ChildrenNode(ChildrenNode<K>),
+    LeftRightNode(LeftRightNode<K>),
+  }
+
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+  #[non_exhaustive]
+  pub enum NodeKind {
+    Children,
+    LeftRight,
+  }
+
+  /// How a child hangs off its parent: by index in a `ChildrenNode`, or in one of the slots of a
+  /// `LeftRightNode`.
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+  pub enum EdgeKind {
+    Child(usize),
+    Left,
+    Right,
   }
 
-  impl Node {
+  impl<K: Key> Node<K> {
+    fn empty(kind: NodeKind, id: K, parent: K) -> Node<K> {
+      match kind {
+        NodeKind::Children => ChildrenNode::new(id, parent),
+        NodeKind::LeftRight => LeftRightNode::new(id, parent),
+      }
+    }
+
+    pub fn kind(&self) -> NodeKind {
+      match self {
+        Node::ChildrenNode(_) => NodeKind::Children,
+        Node::LeftRightNode(_) => NodeKind::LeftRight,
+      }
+    }
+
     fn unpack_inner<T: 'static>(node: &dyn Any) -> Option<&T> {
       match node.downcast_ref::<T>() {
         Some(casted_node) => Some(casted_node),
@@ -70,47 +294,535 @@ pub mod tree {
       }
     }
 
-    pub fn get_inner<T: 'static>(&self) -> Option<&T> {
+    #[deprecated(note = "use `as_children` or `as_left_right` instead")]
+    pub fn get_inner<T: 'static>(&self) -> Option<&T>
+    where
+      K: 'static,
+    {
+      match self {
+        Node::ChildrenNode(inner_node) => Node::<K>::unpack_inner(inner_node),
+        Node::LeftRightNode(inner_node) => Node::<K>::unpack_inner(inner_node),
+      }
+    }
+
+    #[deprecated(note = "use `as_children_mut` or `as_left_right_mut` instead")]
+    pub fn get_inner_mut<T: 'static>(&mut self) -> Option<&mut T>
+    where
+      K: 'static,
+    {
+      match self {
+        Node::ChildrenNode(inner_node) => Node::<K>::unpack_inner_mut(inner_node),
+        Node::LeftRightNode(inner_node) => Node::<K>::unpack_inner_mut(inner_node),
+      }
+    }
+
+    pub fn as_children(&self) -> Option<&ChildrenNode<K>> {
+      match self {
+        Node::ChildrenNode(inner_node) => Some(inner_node),
+        _ => None,
+      }
+    }
+
+    pub fn as_children_mut(&mut self) -> Option<&mut ChildrenNode<K>> {
+      match self {
+        Node::ChildrenNode(inner_node) => Some(inner_node),
+        _ => None,
+      }
+    }
+
+    pub fn as_left_right(&self) -> Option<&LeftRightNode<K>> {
+      match self {
+        Node::LeftRightNode(inner_node) => Some(inner_node),
+        _ => None,
+      }
+    }
+
+    pub fn as_left_right_mut(&mut self) -> Option<&mut LeftRightNode<K>> {
+      match self {
+        Node::LeftRightNode(inner_node) => Some(inner_node),
+        _ => None,
+      }
+    }
+
+    /// Panics if the node is not a `ChildrenNode`.
+    pub fn expect_children(&self) -> &ChildrenNode<K>
+    where
+      K: fmt::Debug,
+    {
+      match self {
+        Node::ChildrenNode(inner_node) => inner_node,
+        Node::LeftRightNode(inner_node) => panic!(
+          "expected node {:?} to be a ChildrenNode, found a LeftRightNode",
+          inner_node.id
+        ),
+      }
+    }
+
+    /// Panics if the node is not a `ChildrenNode`.
+    pub fn expect_children_mut(&mut self) -> &mut ChildrenNode<K>
+    where
+      K: fmt::Debug,
+    {
+      match self {
+        Node::ChildrenNode(inner_node) => inner_node,
+        Node::LeftRightNode(inner_node) => panic!(
+          "expected node {:?} to be a ChildrenNode, found a LeftRightNode",
+          inner_node.id
+        ),
+      }
+    }
+
+    /// Panics if the node is not a `LeftRightNode`.
+    pub fn expect_left_right(&self) -> &LeftRightNode<K>
+    where
+      K: fmt::Debug,
+    {
+      match self {
+        Node::LeftRightNode(inner_node) => inner_node,
+        Node::ChildrenNode(inner_node) => panic!(
+          "expected node {:?} to be a LeftRightNode, found a ChildrenNode",
+          inner_node.id
+        ),
+      }
+    }
+
+    /// Panics if the node is not a `LeftRightNode`.
+    pub fn expect_left_right_mut(&mut self) -> &mut LeftRightNode<K>
+    where
+      K: fmt::Debug,
+    {
+      match self {
+        Node::LeftRightNode(inner_node) => inner_node,
+        Node::ChildrenNode(inner_node) => panic!(
+          "expected node {:?} to be a LeftRightNode, found a ChildrenNode",
+          inner_node.id
+        ),
+      }
+    }
+
+    pub fn id(&self) -> K {
+      match self {
+        Node::ChildrenNode(inner_node) => inner_node.id.clone(),
+        Node::LeftRightNode(inner_node) => inner_node.id.clone(),
+      }
+    }
+
+    pub fn parent(&self) -> K {
+      match self {
+        Node::ChildrenNode(inner_node) => inner_node.parent.clone(),
+        Node::LeftRightNode(inner_node) => inner_node.parent.clone(),
+      }
+    }
+
+    /// Child ids in order: the `children` vec for `ChildrenNode`, left then right for
+    /// `LeftRightNode` (empty slots are skipped).
+    pub fn children(&self) -> ChildIds<'_, K> {
+      match self {
+        Node::ChildrenNode(inner_node) => ChildIds {
+          children: inner_node.children.iter(),
+          left: None,
+          right: None,
+        },
+        Node::LeftRightNode(inner_node) => ChildIds {
+          children: [].iter(),
+          left: inner_node.left.as_ref(),
+          right: inner_node.right.as_ref(),
+        },
+      }
+    }
+
+    pub fn child_count(&self) -> usize {
+      self.children().len()
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+      match self {
+        Node::ChildrenNode(_) => "children",
+        Node::LeftRightNode(_) => "left_right",
+      }
+    }
+
+    fn replace_child_id(&mut self, old_id: K, new_id: K) -> bool {
       match self {
-        Node::ChildrenNode(inner_node) => Node::unpack_inner(inner_node),
-        Node::LeftRightNode(inner_node) => Node::unpack_inner(inner_node),
+        Node::ChildrenNode(inner_node) => {
+          match inner_node.children.iter_mut().find(|id| **id == old_id) {
+            Some(id) => {
+              *id = new_id;
+              true
+            }
+            None => false,
+          }
+        }
+        Node::LeftRightNode(inner_node) => {
+          if inner_node.left.as_ref() == Some(&old_id) {
+            inner_node.left = Some(new_id);
+            true
+          } else if inner_node.right.as_ref() == Some(&old_id) {
+            inner_node.right = Some(new_id);
+            true
+          } else {
+            false
+          }
+        }
       }
     }
 
-    pub fn get_inner_mut<T: 'static>(&mut self) -> Option<&mut T> {
+    fn remap_ids(&mut self, mapping: &HashMap<K, K>) {
+      let remap = |id: &K| mapping.get(id).unwrap_or(id).clone();
       match self {
-        Node::ChildrenNode(inner_node) => Node::unpack_inner_mut(inner_node),
-        Node::LeftRightNode(inner_node) => Node::unpack_inner_mut(inner_node),
+        Node::ChildrenNode(inner_node) => {
+          inner_node.id = remap(&inner_node.id);
+          inner_node.parent = remap(&inner_node.parent);
+          for child_id in inner_node.children.iter_mut() {
+            *child_id = remap(child_id);
+          }
+        }
+        Node::LeftRightNode(inner_node) => {
+          inner_node.id = remap(&inner_node.id);
+          inner_node.parent = remap(&inner_node.parent);
+          inner_node.left = inner_node.left.as_ref().map(remap);
+          inner_node.right = inner_node.right.as_ref().map(remap);
+        }
       }
     }
   }
 
-  #[derive(Debug)]
-  pub struct NodeTree {
-    node_map: HashMap<Uuid, Node>,
+  /// Nodes compare and hash by id only: two nodes are equal when they are the same node, not when
+  /// their contents match. Use the inner structs' `PartialEq` for field-wise comparison.
+  impl<K: Key> PartialEq for Node<K> {
+    fn eq(&self, other: &Node<K>) -> bool {
+      self.id() == other.id()
+    }
+  }
+
+  impl<K: Key> Eq for Node<K> {}
+
+  impl<K: Key> Hash for Node<K> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+      self.id().hash(state);
+    }
+  }
+
+  impl<K: Key> TreeNode<K> for Node<K> {
+    fn id(&self) -> K {
+      Node::id(self)
+    }
+
+    fn parent(&self) -> K {
+      Node::parent(self)
+    }
+
+    fn child_ids(&self) -> Vec<K> {
+      self.children().collect()
+    }
+
+    fn set_parent(&mut self, parent: K) {
+      match self {
+        Node::ChildrenNode(inner_node) => inner_node.set_parent(parent),
+        Node::LeftRightNode(inner_node) => inner_node.set_parent(parent),
+      }
+    }
+
+    fn add_child_id(&mut self, id: K) -> Result<(), Error<K>> {
+      match self {
+        Node::ChildrenNode(inner_node) => inner_node.add_child_id(id),
+        Node::LeftRightNode(inner_node) => inner_node.add_child_id(id),
+      }
+    }
+
+    fn remove_child_id(&mut self, id: K) -> bool {
+      match self {
+        Node::ChildrenNode(inner_node) => inner_node.remove_child_id(id),
+        Node::LeftRightNode(inner_node) => inner_node.remove_child_id(id),
+      }
+    }
+  }
+
+  #[derive(Debug, Clone)]
+  pub struct ChildIds<'a, K = Uuid> {
+    children: std::slice::Iter<'a, K>,
+    left: Option<&'a K>,
+    right: Option<&'a K>,
+  }
+
+  impl<'a, K: Key> Iterator for ChildIds<'a, K> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<K> {
+      if let Some(id) = self.children.next() {
+        return Some(id.clone());
+      }
+      self.left.take().or_else(|| self.right.take()).cloned()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+      let len = self.children.len() + self.left.iter().count() + self.right.iter().count();
+      (len, Some(len))
+    }
+  }
+
+  impl<'a, K: Key> ExactSizeIterator for ChildIds<'a, K> {}
+
+  /// Id of a node known to be a `ChildrenNode`. Obtained from the kind-specific constructors or
+  /// `ChildrenId::try_from_uuid`, so it can't be mixed up with a `LeftRightId`:
+  ///
+  /// ```compile_fail
+  /// use tree_hashmap::tree::tree::NodeTree;
+  ///
+  /// let mut node_tree = NodeTree::new();
+  /// let root_id = node_tree.make_root();
+  /// let id = node_tree.add_left_right_child(root_id).unwrap();
+  /// node_tree.get_children_node(id);
+  /// ```
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+  pub struct ChildrenId(Uuid);
+
+  /// Id of a node known to be a `LeftRightNode`, see `ChildrenId`.
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+  pub struct LeftRightId(Uuid);
+
+  impl ChildrenId {
+    pub fn try_from_uuid(tree: &NodeTree, id: Uuid) -> Result<ChildrenId, Error> {
+      match tree.get_node(id) {
+        Some(Node::ChildrenNode(_)) => Ok(ChildrenId(id)),
+        Some(node) => Err(Error::WrongNodeType {
+          id,
+          expected: "children",
+          found: node.kind_name(),
+        }),
+        None => Err(Error::NotFound(id)),
+      }
+    }
+  }
+
+  impl LeftRightId {
+    pub fn try_from_uuid(tree: &NodeTree, id: Uuid) -> Result<LeftRightId, Error> {
+      match tree.get_node(id) {
+        Some(Node::LeftRightNode(_)) => Ok(LeftRightId(id)),
+        Some(node) => Err(Error::WrongNodeType {
+          id,
+          expected: "left_right",
+          found: node.kind_name(),
+        }),
+        None => Err(Error::NotFound(id)),
+      }
+    }
+  }
+
+  impl std::ops::Deref for ChildrenId {
+    type Target = Uuid;
+
+    fn deref(&self) -> &Uuid {
+      &self.0
+    }
+  }
+
+  impl std::ops::Deref for LeftRightId {
+    type Target = Uuid;
+
+    fn deref(&self) -> &Uuid {
+      &self.0
+    }
+  }
+
+  impl From<ChildrenId> for Uuid {
+    fn from(id: ChildrenId) -> Uuid {
+      id.0
+    }
+  }
+
+  impl From<LeftRightId> for Uuid {
+    fn from(id: LeftRightId) -> Uuid {
+      id.0
+    }
+  }
+
+  pub type NodeFactory<K = Uuid> = Box<dyn Fn(K, &mut Node<K>) -> Node<K>>;
+
+  /// A node id paired with the generation it was inserted with. A handle goes stale once its node
+  /// is removed, and stays stale even if a node with the same id is inserted again later.
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+  pub struct NodeHandle<K = Uuid> {
+    id: K,
+    generation: u64,
   }
 
+  impl<K: Key> NodeHandle<K> {
+    pub fn id(&self) -> K {
+      self.id.clone()
+    }
+  }
+
+  pub type IdGenerator<K = Uuid> = Box<dyn Fn() -> K + Send + Sync>;
+
+  /// Produces the children of a node the first time they are requested, see
+  /// `GenericTree::set_loader`.
+  pub type ChildLoader<K = Uuid> = Box<dyn Fn(K) -> Vec<Node<K>> + Send + Sync>;
+
+  pub const DEFAULT_DEBUG_LIMIT: usize = 100;
+
+  /// A tree of nodes keyed by `K`. Nodes are inserted under ids chosen by the caller (see
+  /// `make_root_with_id` and `make_node_with_id`); `NodeTree` additionally mints fresh `Uuid`s.
+  pub struct GenericTree<K = Uuid> {
+    node_map: HashMap<K, Node<K>>,
+    generations: HashMap<K, u64>,
+    id_generator: Option<IdGenerator<K>>,
+    debug_limit: usize,
+    attachments: HashMap<K, HashMap<TypeId, Box<dyn Any + Send + Sync>>>,
+    loader: Option<ChildLoader<K>>,
+    expanded: HashSet<K>,
+  }
+
+  pub type NodeTree = GenericTree<Uuid>;
+
   #[derive(Debug)]
-  pub enum Error {
-    NotFound,
+  pub enum Error<K = Uuid> {
+    NotFound(K),
+    DuplicateId(K),
+    NoFreeSlot,
+    NoGrandparent,
+    NoPrecedingSibling,
+    NotAChild,
+    WouldCreateCycle,
+    WrongNodeType {
+      id: K,
+      expected: &'static str,
+      found: &'static str,
+    },
+    /// The children of the node have explicit weights (see `GenericTree::set_child_weight`), which
+    /// an order-only operation such as `rotate_children` would discard.
+    WeightedChildren(K),
+    /// `from_newick` input is malformed at byte `position`.
+    InvalidNewick {
+      position: usize,
+      message: &'static str,
+    },
     Unknown,
   }
 
-  impl NodeTree {
-    pub fn new() -> NodeTree {
-      NodeTree {
+  /// The label a node had in Newick input, attached by `NodeTree::from_newick` and preferred
+  /// over the short id by `NodeTree::to_newick`.
+  #[derive(Debug, Clone, PartialEq, Eq)]
+  pub struct NewickLabel(pub String);
+
+  impl<K: Key> GenericTree<K> {
+    pub fn new() -> GenericTree<K> {
+      GenericTree {
         node_map: HashMap::new(),
+        generations: HashMap::new(),
+        id_generator: None,
+        debug_limit: DEFAULT_DEBUG_LIMIT,
+        attachments: HashMap::new(),
+        loader: None,
+        expanded: HashSet::new(),
       }
     }
 
-    pub fn make_root(&mut self) -> Uuid {
-      let id = Uuid::new_v4();
-      let node = ChildrenNode::new(id, id);
+    /// Sets how many nodes the `Debug` output lists before summarizing the rest.
+    pub fn set_debug_limit(&mut self, limit: usize) {
+      self.debug_limit = limit;
+    }
+
+    /// Inserts a root under `id`. Fails with `Error::DuplicateId` if the id is already taken.
+    pub fn make_root_with_id(&mut self, id: K) -> Result<K, Error<K>> {
+      if self.node_map.contains_key(&id) {
+        return Err(Error::DuplicateId(id));
+      }
+      let node = ChildrenNode::new(id.clone(), id.clone());
+      self.insert_node(id.clone(), node);
+      Ok(id)
+    }
+
+    /// Like `NodeTree::make_node`, but inserts the node under the caller's `id`.
+    pub fn make_node_with_id(
+      &mut self,
+      parent_id: K,
+      id: K,
+      factory: NodeFactory<K>,
+    ) -> Result<K, Error<K>> {
+      if self.node_map.contains_key(&id) {
+        return Err(Error::DuplicateId(id));
+      }
+      if let Some(parent) = self.get_node_mut(parent_id.clone()) {
+        let node = factory(id.clone(), parent);
+        self.insert_node(id.clone(), node);
+        Ok(id)
+      } else {
+        Err(Error::NotFound(parent_id))
+      }
+    }
+
+    /// Like `NodeTree::add_child`, but inserts the node under the caller's `id`.
+    pub fn add_child_with_id(
+      &mut self,
+      parent_id: K,
+      id: K,
+      kind: NodeKind,
+    ) -> Result<K, Error<K>> {
+      if self.node_map.contains_key(&id) {
+        return Err(Error::DuplicateId(id));
+      }
+      let parent = self
+        .node_map
+        .get_mut(&parent_id)
+        .ok_or_else(|| Error::NotFound(parent_id.clone()))?;
+      parent.add_child_id(id.clone())?;
+      self.insert_node(id.clone(), Node::empty(kind, id.clone(), parent_id));
+      Ok(id)
+    }
+
+    fn insert_node(&mut self, id: K, node: Node<K>) {
+      *self.generations.entry(id.clone()).or_insert(0) += 1;
       self.node_map.insert(id, node);
-      id
     }
 
-    pub fn get_node(&self, id: Uuid) -> Option<&Node> {
+    fn take_node(&mut self, id: K) -> Option<Node<K>> {
+      self.attachments.remove(&id);
+      self.expanded.remove(&id);
+      self.node_map.remove(&id)
+    }
+
+    fn unlink_from_parent(&mut self, id: K) {
+      let parent_id = match self.node_map.get(&id) {
+        Some(node) => node.parent(),
+        None => return,
+      };
+      if parent_id != id {
+        if let Some(parent) = self.node_map.get_mut(&parent_id) {
+          parent.remove_child_id(id);
+        }
+      }
+    }
+
+    /// Whether `ancestor` is `id` itself or one of its ancestors.
+    fn is_in_subtree(&self, id: K, ancestor: K) -> bool {
+      let mut current = id;
+      for _ in 0..=self.node_map.len() {
+        if current == ancestor {
+          return true;
+        }
+        match self.node_map.get(&current) {
+          Some(node) if node.parent() != current => current = node.parent(),
+          _ => return false,
+        }
+      }
+      false
+    }
+
+    /// Removes `id` and all of its descendants, without touching the parent of `id`.
+    fn take_subtree(&mut self, id: K) -> Vec<(K, Node<K>)> {
+      let mut removed = Vec::new();
+      let mut stack = vec![id];
+      while let Some(id) = stack.pop() {
+        if let Some(node) = self.take_node(id.clone()) {
+          stack.extend(node.children());
+          removed.push((id, node));
+        }
+      }
+      removed
+    }
+
+    pub fn get_node(&self, id: K) -> Option<&Node<K>> {
       if let Some(node) = self.node_map.get(&id) {
         Some(node)
       } else {
@@ -118,7 +830,7 @@ pub mod tree {
       }
     }
 
-    pub fn get_node_mut(&mut self, id: Uuid) -> Option<&mut Node> {
+    pub fn get_node_mut(&mut self, id: K) -> Option<&mut Node<K>> {
       if let Some(node) = self.node_map.get_mut(&id) {
         Some(node)
       } else {
@@ -126,42 +838,4148 @@ pub mod tree {
       }
     }
 
-    pub fn make_node(
-      &mut self,
-      parent_id: Uuid,
-      factory: Box<dyn Fn(Uuid, &mut Node) -> Node>,
-    ) -> Result<Uuid, Error> {
-      let id = Uuid::new_v4();
-      if let Some(parent) = self.get_node_mut(parent_id) {
-        let node = factory(id, parent);
-        self.node_map.insert(id, node);
-        Ok(id)
-      } else {
-        Err(Error::NotFound)
+    pub fn node_ref(&self, id: K) -> Option<NodeRef<'_, K>> {
+      self.node_map.get(&id).map(|node| NodeRef {
+        id: id.clone(),
+        node,
+        tree: self,
+      })
+    }
+
+    pub fn kind_of(&self, id: K) -> Option<NodeKind> {
+      self.node_map.get(&id).map(Node::kind)
+    }
+
+    pub fn count_by_kind(&self) -> HashMap<NodeKind, usize> {
+      let mut counts = HashMap::new();
+      for node in self.node_map.values() {
+        *counts.entry(node.kind()).or_insert(0) += 1;
       }
+      counts
     }
-  }
-}
 
-#[cfg(test)]
-mod tests {
-  use super::tree::{ChildrenNode, LeftRightNode, Node, NodeTree};
+    pub fn handle(&self, id: K) -> Option<NodeHandle<K>> {
+      if !self.node_map.contains_key(&id) {
+        return None;
+      }
+      self.generations.get(&id).map(|generation| NodeHandle {
+        id: id.clone(),
+        generation: *generation,
+      })
+    }
 
-  #[test]
-  fn can_create_node() {
-    let mut node_tree = NodeTree::new();
-    let root_id = node_tree.make_root();
-    let child_1_id = node_tree.make_node(root_id, Box::new(move |id, parent| {
-      let parent_inner = parent.get_inner_mut::<ChildrenNode>().unwrap();
-      parent_inner.children.push(id);
-      LeftRightNode::new(id, parent_inner.id)
-    }));
-    let child_2_id = node_tree.make_node(root_id, Box::new(move |id, parent| {
-      let parent_inner = parent.get_inner_mut::<ChildrenNode>().unwrap();
-      parent_inner.children.push(id);
-      LeftRightNode::new(id, parent_inner.id)
-    }));
-    println!("root_id={:?} child_1_id={:?} child_2_id={:?}", root_id, child_1_id, child_2_id);
-    println!("root_node {:?}", node_tree.get_node(root_id))
+    /// Returns `None` if the handle's node was removed, or if its id has since been reused by a
+    /// newer node.
+    pub fn get_by_handle(&self, handle: NodeHandle<K>) -> Option<&Node<K>> {
+      match self.generations.get(&handle.id) {
+        Some(generation) if *generation == handle.generation => self.node_map.get(&handle.id),
+        _ => None,
+      }
+    }
+
+    /// Iterates over every node in the tree. The iteration order is unspecified.
+    pub fn iter(&self) -> impl Iterator<Item = (K, &Node<K>)> {
+      self.node_map.iter().map(|(id, node)| (id.clone(), node))
+    }
+
+    /// Calls `f` on every node in the tree, in the same unspecified order as `iter`.
+    pub fn for_each<F: FnMut(K, &Node<K>)>(&self, mut f: F) {
+      for (id, node) in self.node_map.iter() {
+        f(id.clone(), node);
+      }
+    }
+
+    /// Like `for_each`, but spreads the calls over the rayon thread pool. `f` only gets shared
+    /// access to the nodes, so no ordering between calls is guaranteed.
+    #[cfg(feature = "rayon")]
+    pub fn par_for_each<F: Fn(K, &Node<K>) + Sync>(&self, f: F)
+    where
+      K: Send + Sync,
+    {
+      use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+      let f = &f;
+      self
+        .node_map
+        .par_iter()
+        .for_each(|(id, node)| f(id.clone(), node));
+    }
+
+    /// Iterates over every node id together with its parent node, or `None` for roots.
+    /// The iteration order is unspecified, same as `iter`.
+    pub fn iter_with_parent(&self) -> impl Iterator<Item = (K, Option<&Node<K>>)> {
+      self.node_map.iter().map(move |(id, node)| {
+        if node.parent() == *id {
+          (id.clone(), None)
+        } else {
+          (id.clone(), self.node_map.get(&node.parent()))
+        }
+      })
+    }
+
+    /// Groups the nodes of the subtree rooted at `root` by depth, in structural order within each
+    /// level. Child ids that are not in the tree are skipped.
+    pub fn levels(&self, root: K) -> Result<Vec<Vec<K>>, Error<K>> {
+      if !self.node_map.contains_key(&root) {
+        return Err(Error::NotFound(root));
+      }
+      let mut levels = Vec::new();
+      let mut level = vec![root];
+      while !level.is_empty() {
+        let next_level = self.next_level(&level);
+        levels.push(level);
+        level = next_level;
+      }
+      Ok(levels)
+    }
+
+    /// Returns the ids at `depth` below `root`, or an empty `Vec` if the subtree is not that deep.
+    pub fn nodes_at_depth(&self, root: K, depth: usize) -> Result<Vec<K>, Error<K>> {
+      if !self.node_map.contains_key(&root) {
+        return Err(Error::NotFound(root));
+      }
+      let mut level = vec![root];
+      for _ in 0..depth {
+        if level.is_empty() {
+          break;
+        }
+        level = self.next_level(&level);
+      }
+      Ok(level)
+    }
+
+    fn next_level(&self, level: &[K]) -> Vec<K> {
+      level
+        .iter()
+        .filter_map(|id| self.node_map.get(id))
+        .flat_map(Node::children)
+        .filter(|child_id| self.node_map.contains_key(child_id))
+        .collect()
+    }
+
+    /// Removes every node deeper than `max_depth` below `root` and returns how many were removed.
+    /// Nodes at exactly `max_depth` are kept but lose their children.
+    pub fn trim_to_depth(&mut self, root: K, max_depth: usize) -> usize {
+      let levels = match self.levels(root) {
+        Ok(levels) => levels,
+        Err(_) => return 0,
+      };
+      if levels.len() <= max_depth + 1 {
+        return 0;
+      }
+      for id in &levels[max_depth] {
+        if let Some(node) = self.node_map.get_mut(id) {
+          for child_id in node.child_ids() {
+            node.remove_child_id(child_id);
+          }
+        }
+      }
+      let mut removed = 0;
+      for id in levels[max_depth + 1..].iter().flatten() {
+        if self.take_node(id.clone()).is_some() {
+          removed += 1;
+        }
+      }
+      removed
+    }
+
+    /// Counts the nodes in the subtree rooted at `start`, including `start` itself, that match
+    /// `pred`.
+    pub fn count_where<F: Fn(&Node<K>) -> bool>(&self, start: K, pred: F) -> usize {
+      let mut count = 0;
+      let mut stack = vec![start];
+      while let Some(id) = stack.pop() {
+        if let Some(node) = self.node_map.get(&id) {
+          if pred(node) {
+            count += 1;
+          }
+          stack.extend(node.children());
+        }
+      }
+      count
+    }
+
+    /// Swaps `id` with its parent: `id` takes the parent's place under the grandparent and the
+    /// former parent becomes the last child of `id`, or takes the first free slot if `id` is a
+    /// `LeftRightNode` (which can be the left slot, before an existing right child). The siblings
+    /// of `id` stay with the former parent. Fails with `Error::NoGrandparent` if `id` is a root or
+    /// a child of a root, and with `Error::NoFreeSlot` if `id` is a full `LeftRightNode`.
+    pub fn promote(&mut self, id: K) -> Result<(), Error<K>> {
+      let node = self
+        .node_map
+        .get(&id)
+        .ok_or_else(|| Error::NotFound(id.clone()))?;
+      let parent_id = node.parent();
+      if parent_id == id {
+        return Err(Error::NoGrandparent);
+      }
+      let grandparent_id = self
+        .node_map
+        .get(&parent_id)
+        .ok_or_else(|| Error::NotFound(parent_id.clone()))?
+        .parent();
+      if grandparent_id == parent_id || !self.node_map.contains_key(&grandparent_id) {
+        return Err(Error::NoGrandparent);
+      }
+      if let Node::LeftRightNode(inner_node) = node {
+        if inner_node.left.is_some() && inner_node.right.is_some() {
+          return Err(Error::NoFreeSlot);
+        }
+      }
+
+      let grandparent = self.node_map.get_mut(&grandparent_id).unwrap();
+      grandparent.replace_child_id(parent_id.clone(), id.clone());
+      let parent = self.node_map.get_mut(&parent_id).unwrap();
+      parent.remove_child_id(id.clone());
+      parent.set_parent(id.clone());
+      let node = self.node_map.get_mut(&id).unwrap();
+      node.set_parent(grandparent_id);
+      node.add_child_id(parent_id)
+    }
+
+    /// Moves `id` to the end of the children of its preceding sibling, which must be a
+    /// `ChildrenNode`. Fails with `Error::NoPrecedingSibling` if `id` is a root or its parent's
+    /// first child.
+    pub fn demote(&mut self, id: K) -> Result<(), Error<K>> {
+      let parent_id = self
+        .node_map
+        .get(&id)
+        .ok_or_else(|| Error::NotFound(id.clone()))?
+        .parent();
+      if parent_id == id {
+        return Err(Error::NoPrecedingSibling);
+      }
+      let parent = self
+        .node_map
+        .get(&parent_id)
+        .ok_or_else(|| Error::NotFound(parent_id.clone()))?;
+      let siblings: Vec<K> = parent.children().collect();
+      let sibling_id = match siblings.iter().position(|sibling_id| *sibling_id == id) {
+        Some(index) if index > 0 => siblings[index - 1].clone(),
+        _ => return Err(Error::NoPrecedingSibling),
+      };
+      match self.node_map.get(&sibling_id) {
+        Some(Node::ChildrenNode(_)) => {}
+        Some(node) => {
+          return Err(Error::WrongNodeType {
+            id: sibling_id,
+            expected: "children",
+            found: node.kind_name(),
+          })
+        }
+        None => return Err(Error::NotFound(sibling_id)),
+      }
+
+      self
+        .node_map
+        .get_mut(&parent_id)
+        .unwrap()
+        .remove_child_id(id.clone());
+      self
+        .node_map
+        .get_mut(&sibling_id)
+        .unwrap()
+        .add_child_id(id.clone())?;
+      self.node_map.get_mut(&id).unwrap().set_parent(sibling_id);
+      Ok(())
+    }
+
+    /// Changes the weight of `child_id` and moves it to its new position among the children of
+    /// `parent_id`, after any siblings with the same weight.
+    pub fn set_child_weight(
+      &mut self,
+      parent_id: K,
+      child_id: K,
+      weight: i64,
+    ) -> Result<(), Error<K>> {
+      let parent = match self.node_map.get_mut(&parent_id) {
+        Some(Node::ChildrenNode(inner_node)) => inner_node,
+        Some(node) => {
+          return Err(Error::WrongNodeType {
+            id: parent_id,
+            expected: "children",
+            found: node.kind_name(),
+          })
+        }
+        None => return Err(Error::NotFound(parent_id)),
+      };
+      if !parent.remove_child_id(child_id.clone()) {
+        return Err(Error::NotAChild);
+      }
+      parent.insert_child_weighted(child_id, weight);
+      Ok(())
+    }
+
+    /// Walks the subtree rooted at `root` and removes every subtree whose root matches `pred`,
+    /// without descending into it. Returns the ids of the removed subtree roots.
+    pub fn prune_subtrees(&mut self, root: K, pred: impl Fn(K, &Node<K>) -> bool) -> Vec<K> {
+      let mut pruned = Vec::new();
+      let mut stack = vec![root];
+      while let Some(id) = stack.pop() {
+        if let Some(node) = self.node_map.get(&id) {
+          if pred(id.clone(), node) {
+            pruned.push(id);
+          } else {
+            let children: Vec<K> = node.children().collect();
+            stack.extend(children.into_iter().rev());
+          }
+        }
+      }
+      for id in &pruned {
+        self.unlink_from_parent(id.clone());
+        self.take_subtree(id.clone());
+      }
+      pruned
+    }
+
+    /// Appends every child of `from` to `to`, leaving `from` in place without children. If `to`
+    /// is a `LeftRightNode` the children must fit in its free slots, otherwise nothing is moved
+    /// and `Error::NoFreeSlot` is returned.
+    pub fn move_children(&mut self, from: K, to: K) -> Result<(), Error<K>> {
+      let children: Vec<K> = self
+        .node_map
+        .get(&from)
+        .ok_or_else(|| Error::NotFound(from.clone()))?
+        .children()
+        .collect();
+      let target = self
+        .node_map
+        .get(&to)
+        .ok_or_else(|| Error::NotFound(to.clone()))?;
+      if from == to {
+        return Ok(());
+      }
+      if self.is_in_subtree(to.clone(), from.clone()) {
+        return Err(Error::WouldCreateCycle);
+      }
+      if target.kind() == NodeKind::LeftRight && children.len() > 2 - target.child_count() {
+        return Err(Error::NoFreeSlot);
+      }
+
+      let source = self.node_map.get_mut(&from).unwrap();
+      for child_id in &children {
+        source.remove_child_id(child_id.clone());
+      }
+      let target = self.node_map.get_mut(&to).unwrap();
+      for child_id in &children {
+        target.add_child_id(child_id.clone())?;
+      }
+      for child_id in &children {
+        if let Some(child) = self.node_map.get_mut(child_id) {
+          child.set_parent(to.clone());
+        }
+      }
+      Ok(())
+    }
+
+    /// Describes how the subtree rooted at `self_root` differs in shape from the one rooted at
+    /// `other_root` in `other`, or returns `None` if they have the same shape. Ids are ignored.
+    /// Each line names the structural path (child indices from the root, `0`/`1` for the left and
+    /// right slots of a `LeftRightNode`) where the trees diverge; only the first
+    /// `EXPLAINED_DIFFERENCES_LIMIT` differences are spelled out.
+    pub fn explain_difference(
+      &self,
+      self_root: K,
+      other: &GenericTree<K>,
+      other_root: K,
+    ) -> Option<String> {
+      let differences = self.structural_differences(self_root, other, other_root, None);
+      if differences.is_empty() {
+        return None;
+      }
+      let mut lines: Vec<String> = differences
+        .iter()
+        .take(EXPLAINED_DIFFERENCES_LIMIT)
+        .cloned()
+        .collect();
+      if differences.len() > EXPLAINED_DIFFERENCES_LIMIT {
+        lines.push(format!(
+          "… and {} more differences",
+          differences.len() - EXPLAINED_DIFFERENCES_LIMIT
+        ));
+      }
+      Some(lines.join("\n"))
+    }
+
+    /// Compares the two subtrees in depth-first order and describes every divergence, stopping
+    /// after `limit` of them if given. Children below a divergence in node kind are not compared.
+    fn structural_differences(
+      &self,
+      self_root: K,
+      other: &GenericTree<K>,
+      other_root: K,
+      limit: Option<usize>,
+    ) -> Vec<String> {
+      let mut differences = Vec::new();
+      let mut stack = vec![(Some(self_root), Some(other_root), Vec::<usize>::new())];
+      while let Some((left_id, right_id, path)) = stack.pop() {
+        if limit.is_some_and(|limit| differences.len() >= limit) {
+          break;
+        }
+        let at = if path.is_empty() {
+          "at root".to_string()
+        } else {
+          let indices: Vec<String> = path.iter().map(usize::to_string).collect();
+          format!("at path {}", indices.join("/"))
+        };
+        let left = left_id.and_then(|id| self.node_map.get(&id));
+        let right = right_id.and_then(|id| other.node_map.get(&id));
+        let (left, right) = match (left, right) {
+          (None, None) => continue,
+          (Some(left), None) => {
+            differences.push(format!(
+              "{}: right tree has no node, left tree has {}",
+              at,
+              describe(left)
+            ));
+            continue;
+          }
+          (None, Some(right)) => {
+            differences.push(format!(
+              "{}: left tree has no node, right tree has {}",
+              at,
+              describe(right)
+            ));
+            continue;
+          }
+          (Some(left), Some(right)) => (left, right),
+        };
+        let mut pairs: Vec<(Option<K>, Option<K>)> = Vec::new();
+        match (left, right) {
+          (Node::ChildrenNode(left_inner), Node::ChildrenNode(right_inner)) => {
+            let (left_len, right_len) = (left_inner.children.len(), right_inner.children.len());
+            if left_len != right_len {
+              let (side, extra) = if left_len > right_len {
+                ("left", right_len)
+              } else {
+                ("right", left_len)
+              };
+              differences.push(format!(
+                "{}: left has {}, right has {} (extra {} child at index {})",
+                at,
+                count_children(left_len),
+                count_children(right_len),
+                side,
+                extra
+              ));
+            }
+            for index in 0..left_len.min(right_len) {
+              pairs.push((
+                Some(left_inner.children[index].clone()),
+                Some(right_inner.children[index].clone()),
+              ));
+            }
+          }
+          (Node::LeftRightNode(left_inner), Node::LeftRightNode(right_inner)) => {
+            pairs.push((left_inner.left.clone(), right_inner.left.clone()));
+            pairs.push((left_inner.right.clone(), right_inner.right.clone()));
+          }
+          _ => {
+            differences.push(format!(
+              "{}: left tree has {}, right tree has {}",
+              at,
+              describe(left),
+              describe(right)
+            ));
+          }
+        }
+        for (index, (left_child, right_child)) in pairs.into_iter().enumerate().rev() {
+          let mut child_path = path.clone();
+          child_path.push(index);
+          stack.push((left_child, right_child, child_path));
+        }
+      }
+      differences
+    }
+
+    /// Returns the deepest node below `root` and its depth, preferring the leftmost one on ties.
+    pub fn deepest_node(&self, root: K) -> Option<(K, usize)> {
+      let levels = self.levels(root).ok()?;
+      let depth = levels.len() - 1;
+      Some((levels[depth][0].clone(), depth))
+    }
+
+    /// Returns the depth and node count of the widest level below `root`, preferring the
+    /// shallowest one on ties.
+    pub fn widest_level(&self, root: K) -> Option<(usize, usize)> {
+      let levels = self.levels(root).ok()?;
+      let mut widest = (0, levels[0].len());
+      for (depth, level) in levels.iter().enumerate() {
+        if level.len() > widest.1 {
+          widest = (depth, level.len());
+        }
+      }
+      Some(widest)
+    }
+
+    /// Lazily yields every non-root node whose recorded parent is missing from the tree, or
+    /// exists but doesn't list the node among its children. The tree is not modified.
+    pub fn orphans(&self) -> impl Iterator<Item = (K, &Node<K>)> {
+      self.node_map.iter().filter_map(move |(id, node)| {
+        let parent_id = node.parent();
+        if parent_id == *id {
+          return None;
+        }
+        match self.node_map.get(&parent_id) {
+          Some(parent) if parent.children().any(|child_id| child_id == *id) => None,
+          _ => Some((id.clone(), node)),
+        }
+      })
+    }
+
+    /// Removes every node in `ids` together with its descendants and detaches them from their
+    /// parents. Ids that are missing, repeated or inside an already removed subtree are skipped.
+    /// Returns the ids of all removed nodes.
+    pub fn remove_many(&mut self, ids: &[K]) -> Vec<K> {
+      self
+        .take_subtrees(ids)
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect()
+    }
+
+    /// Removes every node matching `pred` together with its descendants, detaching them from
+    /// their parents, and returns the removed nodes. The order is unspecified. Removed nodes keep
+    /// their child lists intact, even when a descendant matched as well.
+    pub fn drain_where<F: Fn(&Node<K>) -> bool>(&mut self, pred: F) -> Vec<(K, Node<K>)> {
+      let matching: HashSet<K> = self
+        .node_map
+        .iter()
+        .filter(|(_, node)| pred(node))
+        .map(|(id, _)| id.clone())
+        .collect();
+      let topmost: Vec<K> = matching
+        .iter()
+        .filter(|id| !self.has_ancestor_in(id, &matching))
+        .cloned()
+        .collect();
+      self.take_subtrees(&topmost)
+    }
+
+    /// Whether following parent links up from `id` reaches a node in `set`.
+    fn has_ancestor_in(&self, id: &K, set: &HashSet<K>) -> bool {
+      let mut current = id.clone();
+      for _ in 0..self.node_map.len() {
+        let parent_id = match self.node_map.get(&current) {
+          Some(node) => node.parent(),
+          None => return false,
+        };
+        if parent_id == current {
+          return false;
+        }
+        if set.contains(&parent_id) {
+          return true;
+        }
+        current = parent_id;
+      }
+      false
+    }
+
+    /// Unlinks and removes the subtree of each id in `ids`, skipping ids already removed.
+    fn take_subtrees(&mut self, ids: &[K]) -> Vec<(K, Node<K>)> {
+      let mut removed = Vec::new();
+      for id in ids {
+        if !self.node_map.contains_key(id) {
+          continue;
+        }
+        self.unlink_from_parent(id.clone());
+        removed.extend(self.take_subtree(id.clone()));
+      }
+      removed
+    }
+
+    /// Returns `(id, parent, kind_name)` for every node in the subtree rooted at `root`, in
+    /// depth-first pre-order, or `None` if `root` is not in the tree.
+    pub fn subtree_snapshot(&self, root: K) -> Option<Vec<(K, K, &'static str)>> {
+      if !self.node_map.contains_key(&root) {
+        return None;
+      }
+      let mut snapshot = Vec::new();
+      let mut stack = vec![root];
+      while let Some(id) = stack.pop() {
+        if let Some(node) = self.node_map.get(&id) {
+          let children: Vec<K> = node.children().collect();
+          stack.extend(children.into_iter().rev());
+          snapshot.push((id, node.parent(), node.kind_name()));
+        }
+      }
+      Some(snapshot)
+    }
+
+    /// Rebuilds the `LeftRightNode` subtree rooted at `root` as a height-balanced binary tree
+    /// that keeps the in-order sequence of its nodes, and returns the new subtree root. The new
+    /// root takes the old root's place under its parent. Nodes are relinked, not recreated, so
+    /// ids and handles stay valid. Fails with `Error::WrongNodeType` if the subtree contains a
+    /// `ChildrenNode`, without changing anything.
+    pub fn rebalance(&mut self, root: K) -> Result<K, Error<K>> {
+      let parent_id = self
+        .node_map
+        .get(&root)
+        .ok_or_else(|| Error::NotFound(root.clone()))?
+        .parent();
+      let mut in_order = Vec::new();
+      let mut stack = Vec::new();
+      let mut current = Some(root.clone());
+      while current.is_some() || !stack.is_empty() {
+        while let Some(id) = current {
+          let inner_node = match self.node_map.get(&id) {
+            Some(Node::LeftRightNode(inner_node)) => inner_node,
+            Some(node) => {
+              return Err(Error::WrongNodeType {
+                id,
+                expected: "left_right",
+                found: node.kind_name(),
+              })
+            }
+            None => return Err(Error::NotFound(id)),
+          };
+          current = inner_node.left.clone();
+          stack.push(id);
+        }
+        let id = stack.pop().unwrap();
+        current = self.node_map[&id]
+          .as_left_right()
+          .and_then(|inner_node| inner_node.right.clone());
+        in_order.push(id);
+      }
+
+      let new_root = self.link_balanced(&in_order).unwrap();
+      if parent_id == root {
+        self
+          .node_map
+          .get_mut(&new_root)
+          .unwrap()
+          .set_parent(new_root.clone());
+      } else {
+        self
+          .node_map
+          .get_mut(&new_root)
+          .unwrap()
+          .set_parent(parent_id.clone());
+        if let Some(parent) = self.node_map.get_mut(&parent_id) {
+          parent.replace_child_id(root, new_root.clone());
+        }
+      }
+      Ok(new_root)
+    }
+
+    /// Links the `LeftRightNode`s in `ids` into a balanced tree in in-order and returns its root.
+    /// The parent of the returned root is left for the caller to set.
+    fn link_balanced(&mut self, ids: &[K]) -> Option<K> {
+      if ids.is_empty() {
+        return None;
+      }
+      let middle = ids.len() / 2;
+      let id = ids[middle].clone();
+      let left = self.link_balanced(&ids[..middle]);
+      let right = self.link_balanced(&ids[middle + 1..]);
+      for child_id in left.iter().chain(right.iter()) {
+        self
+          .node_map
+          .get_mut(child_id)
+          .unwrap()
+          .set_parent(id.clone());
+      }
+      if let Some(Node::LeftRightNode(inner_node)) = self.node_map.get_mut(&id) {
+        inner_node.left = left;
+        inner_node.right = right;
+      }
+      Some(id)
+    }
+
+    /// Runs `f` against the node `id` if it exists and returns its result.
+    pub fn with_node<R, F: FnOnce(&Node<K>) -> R>(&self, id: K, f: F) -> Option<R> {
+      self.node_map.get(&id).map(f)
+    }
+
+    /// Runs `f` against the node `id` if it exists and returns its result. The mutable borrow
+    /// ends when `f` returns.
+    pub fn with_node_mut<R, F: FnOnce(&mut Node<K>) -> R>(&mut self, id: K, f: F) -> Option<R> {
+      self.node_map.get_mut(&id).map(f)
+    }
+
+    /// Number of levels in the subtree rooted at `root`, counting nodes rather than edges: a
+    /// single node has height 1. Returns `None` if `root` is not in the tree.
+    pub fn tree_height(&self, root: K) -> Option<usize> {
+      self.levels(root).ok().map(|levels| levels.len())
+    }
+
+    /// Stores `value` alongside the node `id`, replacing any earlier attachment of the same type.
+    /// Attachments are dropped when their node is removed. They must be `Send + Sync` so the tree
+    /// can still be shared across threads.
+    pub fn attach<T: Send + Sync + 'static>(&mut self, id: K, value: T) -> Result<(), Error<K>> {
+      if !self.node_map.contains_key(&id) {
+        return Err(Error::NotFound(id));
+      }
+      self
+        .attachments
+        .entry(id)
+        .or_default()
+        .insert(TypeId::of::<T>(), Box::new(value));
+      Ok(())
+    }
+
+    pub fn attachment<T: 'static>(&self, id: K) -> Option<&T> {
+      self
+        .attachments
+        .get(&id)?
+        .get(&TypeId::of::<T>())?
+        .downcast_ref::<T>()
+    }
+
+    pub fn attachment_mut<T: 'static>(&mut self, id: K) -> Option<&mut T> {
+      self
+        .attachments
+        .get_mut(&id)?
+        .get_mut(&TypeId::of::<T>())?
+        .downcast_mut::<T>()
+    }
+
+    /// Removes and returns the attachment of type `T` from the node `id`.
+    pub fn detach<T: 'static>(&mut self, id: K) -> Option<T> {
+      let attachments = self.attachments.get_mut(&id)?;
+      let value = attachments.remove(&TypeId::of::<T>())?;
+      if attachments.is_empty() {
+        self.attachments.remove(&id);
+      }
+      value.downcast::<T>().ok().map(|value| *value)
+    }
+
+    /// Yields `id` followed by its ancestors up to the root. Yields nothing if `id` is not in the
+    /// tree, and stops early at a parent that is missing from the tree.
+    pub fn ancestors_inclusive(&self, id: K) -> impl Iterator<Item = K> + '_ {
+      let start = Some(id).filter(|id| self.node_map.contains_key(id));
+      std::iter::successors(start, move |id| {
+        let parent_id = self.node_map.get(id)?.parent();
+        Some(parent_id).filter(|parent_id| parent_id != id && self.node_map.contains_key(parent_id))
+      })
+      .take(self.node_map.len())
+    }
+
+    /// Like `ancestors_inclusive`, without `id` itself.
+    pub fn ancestors(&self, id: K) -> impl Iterator<Item = K> + '_ {
+      self.ancestors_inclusive(id).skip(1)
+    }
+
+    /// Whether the subtrees rooted at `a_root` and at `b_root` in `other` have the same topology
+    /// and node kinds, ignoring ids. See `explain_difference` for what differs. Returns `false`
+    /// if either root is missing.
+    pub fn same_shape(&self, a_root: K, other: &GenericTree<K>, b_root: K) -> bool {
+      self.node_map.contains_key(&a_root)
+        && other.node_map.contains_key(&b_root)
+        && self
+          .structural_differences(a_root, other, b_root, Some(1))
+          .is_empty()
+    }
+
+    /// Ids of the subtree rooted at `root` in depth-first pre-order: each node before its
+    /// children. Empty if `root` is not in the tree.
+    pub fn preorder_ids(&self, root: K) -> Vec<K> {
+      let mut ids = Vec::new();
+      let mut stack = vec![root];
+      while let Some(id) = stack.pop() {
+        if let Some(node) = self.node_map.get(&id) {
+          let children: Vec<K> = node.children().collect();
+          stack.extend(children.into_iter().rev());
+          ids.push(id);
+        }
+      }
+      ids
+    }
+
+    /// Ids of the subtree rooted at `root` in depth-first post-order: children (left before
+    /// right) before their parent, so it is safe for bottom-up processing. Empty if `root` is
+    /// not in the tree.
+    pub fn postorder_ids(&self, root: K) -> Vec<K> {
+      let mut ids = Vec::new();
+      let mut stack = vec![(root, false)];
+      while let Some((id, expanded)) = stack.pop() {
+        if expanded {
+          ids.push(id);
+        } else if let Some(node) = self.node_map.get(&id) {
+          let children: Vec<K> = node.children().collect();
+          stack.push((id, true));
+          stack.extend(children.into_iter().rev().map(|child_id| (child_id, false)));
+        }
+      }
+      ids
+    }
+
+    /// Read-only access to the underlying id to node map, for analyses the tree doesn't provide.
+    /// This exposes the internal representation: the map type and what it holds (for instance
+    /// whether nodes without a parent link are kept) may change between versions.
+    pub fn as_map(&self) -> &HashMap<K, Node<K>> {
+      &self.node_map
+    }
+
+    /// Returns the inner struct of the node `id` as `T` (`ChildrenNode` or `LeftRightNode`).
+    /// Fails with `Error::NotFound` if the node is missing and `Error::WrongNodeType` if it is
+    /// of a different kind.
+    pub fn inner_of<T: 'static>(&self, id: K) -> Result<&T, Error<K>>
+    where
+      K: 'static,
+    {
+      let node = match self.node_map.get(&id) {
+        Some(node) => node,
+        None => return Err(Error::NotFound(id)),
+      };
+      let inner: &dyn Any = match node {
+        Node::ChildrenNode(inner_node) => inner_node,
+        Node::LeftRightNode(inner_node) => inner_node,
+      };
+      inner.downcast_ref::<T>().ok_or_else(|| {
+        let expected = if TypeId::of::<T>() == TypeId::of::<ChildrenNode<K>>() {
+          "children"
+        } else if TypeId::of::<T>() == TypeId::of::<LeftRightNode<K>>() {
+          "left_right"
+        } else {
+          std::any::type_name::<T>()
+        };
+        Error::WrongNodeType {
+          id,
+          expected,
+          found: node.kind_name(),
+        }
+      })
+    }
+
+    /// Makes the tree a lazy view over a backing store: the first `children_of` call for a node
+    /// passes its id to `loader` and inserts the returned nodes as its children.
+    pub fn set_loader(&mut self, loader: ChildLoader<K>) {
+      self.loader = Some(loader);
+    }
+
+    /// Whether `children_of` has already run the loader for `id`.
+    pub fn is_expanded(&self, id: K) -> bool {
+      self.expanded.contains(&id)
+    }
+
+    /// Returns the child ids of `id`, running the loader first if one is set and `id` hasn't
+    /// been expanded yet. Loaded nodes are appended after any existing children and have their
+    /// parent set to `id`. The loader runs at most once per node, even when its nodes are
+    /// rejected: with `Error::DuplicateId` if one of their ids is taken, or `Error::NoFreeSlot`
+    /// if they don't fit a `LeftRightNode`.
+    pub fn children_of(&mut self, id: K) -> Result<Vec<K>, Error<K>> {
+      let node = self
+        .node_map
+        .get(&id)
+        .ok_or_else(|| Error::NotFound(id.clone()))?;
+      if self.loader.is_none() || self.expanded.contains(&id) {
+        return Ok(node.children().collect());
+      }
+      let free_slots = match node {
+        Node::ChildrenNode(_) => usize::MAX,
+        Node::LeftRightNode(_) => 2 - node.child_count(),
+      };
+      self.expanded.insert(id.clone());
+      let loaded = (self.loader.as_ref().unwrap())(id.clone());
+
+      let mut loaded_ids = HashSet::new();
+      for child in &loaded {
+        let child_id = child.id();
+        if self.node_map.contains_key(&child_id) || !loaded_ids.insert(child_id.clone()) {
+          return Err(Error::DuplicateId(child_id));
+        }
+      }
+      if loaded.len() > free_slots {
+        return Err(Error::NoFreeSlot);
+      }
+      for mut child in loaded {
+        let child_id = child.id();
+        child.set_parent(id.clone());
+        self
+          .node_map
+          .get_mut(&id)
+          .unwrap()
+          .add_child_id(child_id.clone())?;
+        self.insert_node(child_id, child);
+      }
+      Ok(self.node_map[&id].children().collect())
+    }
+
+    /// Rotates the children of the `ChildrenNode` `id` left by `n` positions, or right for
+    /// negative `n`, wrapping around. Only works on children in their default weights `0, 1, ...`;
+    /// fails with `Error::WeightedChildren` if weights were set explicitly, since rotating would
+    /// have to throw them away.
+    pub fn rotate_children(&mut self, id: K, n: isize) -> Result<(), Error<K>> {
+      let inner_node = match self.node_map.get_mut(&id) {
+        Some(Node::ChildrenNode(inner_node)) => inner_node,
+        Some(node) => {
+          return Err(Error::WrongNodeType {
+            id,
+            expected: "children",
+            found: node.kind_name(),
+          })
+        }
+        None => return Err(Error::NotFound(id)),
+      };
+      if !inner_node.weights.is_empty() {
+        return Err(Error::WeightedChildren(id));
+      }
+      let len = inner_node.children.len();
+      if len < 2 {
+        return Ok(());
+      }
+      let shift = n.rem_euclid(len as isize) as usize;
+      inner_node.children.rotate_left(shift);
+      Ok(())
+    }
+
+    /// Whether following parent links from some node ever revisits a node, other than a root
+    /// pointing at itself. Runs in O(n): every node is walked through at most once.
+    pub fn has_cycle(&self) -> bool {
+      let mut finished: HashSet<&K> = HashSet::with_capacity(self.node_map.len());
+      for start in self.node_map.keys() {
+        let mut path: HashSet<&K> = HashSet::new();
+        let mut current = start;
+        while !finished.contains(current) {
+          if !path.insert(current) {
+            return true;
+          }
+          match self
+            .node_map
+            .get_key_value(&self.node_map[current].parent())
+          {
+            Some((parent_id, _)) if parent_id != current => current = parent_id,
+            _ => break,
+          }
+        }
+        finished.extend(path);
+      }
+      false
+    }
+
+    /// Removes every node that can't be reached from a root through child links, and returns
+    /// their ids in unspecified order.
+    pub fn prune_unreachable(&mut self) -> Vec<K> {
+      let mut reachable: HashSet<K> = HashSet::with_capacity(self.node_map.len());
+      let mut stack: Vec<K> = self
+        .node_map
+        .iter()
+        .filter(|(id, node)| node.parent() == **id)
+        .map(|(id, _)| id.clone())
+        .collect();
+      while let Some(id) = stack.pop() {
+        if let Some(node) = self.node_map.get(&id) {
+          if reachable.insert(id) {
+            stack.extend(node.children());
+          }
+        }
+      }
+      let unreachable: Vec<K> = self
+        .node_map
+        .keys()
+        .filter(|id| !reachable.contains(*id))
+        .cloned()
+        .collect();
+      for id in &unreachable {
+        self.take_node(id.clone());
+      }
+      unreachable
+    }
+
+    /// Calls `f(parent, child, kind)` for every edge in the subtree rooted at `root`. Edges are
+    /// grouped by parent: each node reports all of its outgoing edges, in child order, before any
+    /// of its children are visited, and parents are visited in depth-first pre-order.
+    pub fn for_each_edge<F: FnMut(K, K, EdgeKind)>(&self, root: K, mut f: F) {
+      let mut stack = vec![root];
+      while let Some(id) = stack.pop() {
+        let mut edges: Vec<(K, EdgeKind)> = match self.node_map.get(&id) {
+          Some(Node::ChildrenNode(inner_node)) => inner_node
+            .children
+            .iter()
+            .enumerate()
+            .map(|(index, child_id)| (child_id.clone(), EdgeKind::Child(index)))
+            .collect(),
+          Some(Node::LeftRightNode(inner_node)) => inner_node
+            .left
+            .iter()
+            .map(|left| (left.clone(), EdgeKind::Left))
+            .chain(
+              inner_node
+                .right
+                .iter()
+                .map(|right| (right.clone(), EdgeKind::Right)),
+            )
+            .collect(),
+          None => continue,
+        };
+        for (child_id, kind) in &edges {
+          f(id.clone(), child_id.clone(), *kind);
+        }
+        edges.reverse();
+        stack.extend(edges.into_iter().map(|(child_id, _)| child_id));
+      }
+    }
+
+    /// Number of children of `id` without collecting them, or 0 if `id` is not in the tree.
+    pub fn child_count(&self, id: K) -> usize {
+      self.node_map.get(&id).map_or(0, Node::child_count)
+    }
+
+    /// Re-roots the tree at `new_root` by reversing the parent links on the path up to the old
+    /// root. Each node on the path takes its former parent as a child in the slot its former
+    /// child on the path used; `new_root` appends its former parent as a new child, so it needs a
+    /// free slot if it is a `LeftRightNode`. The rest of the structure is unchanged.
+    pub fn set_root(&mut self, new_root: K) -> Result<(), Error<K>> {
+      let path: Vec<K> = self.ancestors_inclusive(new_root.clone()).collect();
+      let old_root = match path.last() {
+        Some(old_root) => old_root.clone(),
+        None => return Err(Error::NotFound(new_root)),
+      };
+      let top_parent = self.node_map[&old_root].parent();
+      if top_parent != old_root {
+        return Err(if self.node_map.contains_key(&top_parent) {
+          Error::WouldCreateCycle
+        } else {
+          Error::NotFound(top_parent)
+        });
+      }
+      if path.len() == 1 {
+        return Ok(());
+      }
+      let node = &self.node_map[&new_root];
+      if node.kind() == NodeKind::LeftRight && node.child_count() == 2 {
+        return Err(Error::NoFreeSlot);
+      }
+
+      for index in 0..path.len() {
+        let node = self.node_map.get_mut(&path[index]).unwrap();
+        if index == 0 {
+          node.add_child_id(path[1].clone())?;
+        } else if index + 1 < path.len() {
+          node.replace_child_id(path[index - 1].clone(), path[index + 1].clone());
+        } else {
+          node.remove_child_id(path[index - 1].clone());
+        }
+        let parent = if index == 0 {
+          &path[0]
+        } else {
+          &path[index - 1]
+        };
+        node.set_parent(parent.clone());
+      }
+      Ok(())
+    }
+
+    /// Whether every node below `root`, `root` included, is a `LeftRightNode` with either no
+    /// children or both. `false` if the subtree contains a `ChildrenNode` or `root` is missing.
+    pub fn is_full_binary(&self, root: K) -> bool {
+      if !self.node_map.contains_key(&root) {
+        return false;
+      }
+      let mut stack = vec![root];
+      while let Some(id) = stack.pop() {
+        match self.node_map.get(&id) {
+          Some(Node::LeftRightNode(inner_node)) => {
+            match (inner_node.left.clone(), inner_node.right.clone()) {
+              (Some(left), Some(right)) => {
+                stack.push(left);
+                stack.push(right);
+              }
+              (None, None) => {}
+              _ => return false,
+            }
+          }
+          _ => return false,
+        }
+      }
+      true
+    }
+
+    /// Whether the subtree rooted at `root` is a complete binary tree of `LeftRightNode`s: every
+    /// level is full except possibly the last, which is filled from the left. `false` if the
+    /// subtree contains a `ChildrenNode` or `root` is missing.
+    pub fn is_complete_binary(&self, root: K) -> bool {
+      let mut queue = VecDeque::new();
+      queue.push_back(Some(root));
+      let mut seen_gap = false;
+      while let Some(slot) = queue.pop_front() {
+        let id = match slot {
+          Some(id) => id,
+          None => {
+            seen_gap = true;
+            continue;
+          }
+        };
+        if seen_gap {
+          return false;
+        }
+        match self.node_map.get(&id) {
+          Some(Node::LeftRightNode(inner_node)) => {
+            queue.push_back(inner_node.left.clone());
+            queue.push_back(inner_node.right.clone());
+          }
+          _ => return false,
+        }
+      }
+      true
+    }
+
+    /// Gets the entry for `id` for in-place get-or-insert logic, like `HashMap::entry`.
+    pub fn entry(&mut self, id: K) -> Entry<'_, K> {
+      if self.node_map.contains_key(&id) {
+        Entry::Occupied(OccupiedEntry { id, tree: self })
+      } else {
+        Entry::Vacant(VacantEntry { id, tree: self })
+      }
+    }
+
+    /// Every descendant of `root` with its depth below `root`, shallowest first and in
+    /// depth-first order within a depth. `root` itself is not included. Empty if `root` is
+    /// missing.
+    pub fn descendants_by_depth(&self, root: K) -> Vec<(usize, K)> {
+      let levels = self.levels(root).unwrap_or_default();
+      levels
+        .into_iter()
+        .enumerate()
+        .skip(1)
+        .flat_map(|(depth, level)| level.into_iter().map(move |id| (depth, id)))
+        .collect()
+    }
+
+    /// Lazily walks the subtree rooted at `start` in depth-first pre-order and yields only the
+    /// nodes of `kind`, still descending through nodes of other kinds.
+    pub fn dfs_filtered(&self, start: K, kind: NodeKind) -> impl Iterator<Item = K> + '_ {
+      let mut stack = vec![start];
+      std::iter::from_fn(move || {
+        while let Some(id) = stack.pop() {
+          if let Some(node) = self.node_map.get(&id) {
+            let children: Vec<K> = node.children().collect();
+            stack.extend(children.into_iter().rev());
+            if node.kind() == kind {
+              return Some(id);
+            }
+          }
+        }
+        None
+      })
+    }
+
+    /// Consumes the tree and returns the subtree rooted at `root` as a `NestedNode`, or `None`
+    /// if `root` is missing. Ids are dropped.
+    pub fn into_nested(mut self, root: K) -> Option<NestedNode> {
+      let mut built: HashMap<K, NestedNode> = HashMap::new();
+      for id in self.postorder_ids(root.clone()) {
+        let nested = match self.take_node(id.clone())? {
+          Node::ChildrenNode(inner_node) => NestedNode::Children(
+            inner_node
+              .children
+              .iter()
+              .filter_map(|child_id| built.remove(child_id))
+              .collect(),
+          ),
+          Node::LeftRightNode(inner_node) => {
+            let mut take = |slot: Option<K>| slot.and_then(|child_id| built.remove(&child_id));
+            NestedNode::LeftRight(
+              take(inner_node.left).map(Box::new),
+              take(inner_node.right).map(Box::new),
+            )
+          }
+        };
+        built.insert(id, nested);
+      }
+      built.remove(&root)
+    }
+
+    /// Returns the index path from the root to the lowest common ancestor of `a` and `b`: one
+    /// child index per level, with `0`/`1` for the left and right slots of a `LeftRightNode`, as
+    /// in `explain_difference`. The root has the empty path. Returns `None` if either node is
+    /// missing or they are in different trees.
+    pub fn common_ancestor_path(&self, a: K, b: K) -> Option<Vec<usize>> {
+      let a_ancestors: HashSet<K> = self.ancestors_inclusive(a).collect();
+      let lca = self
+        .ancestors_inclusive(b)
+        .find(|id| a_ancestors.contains(id))?;
+      let mut chain: Vec<K> = self.ancestors_inclusive(lca).collect();
+      chain.reverse();
+      chain
+        .windows(2)
+        .map(|pair| match &self.node_map[&pair[0]] {
+          Node::ChildrenNode(inner_node) => {
+            inner_node.children.iter().position(|id| *id == pair[1])
+          }
+          Node::LeftRightNode(inner_node) => {
+            if inner_node.left.as_ref() == Some(&pair[1]) {
+              Some(0)
+            } else if inner_node.right.as_ref() == Some(&pair[1]) {
+              Some(1)
+            } else {
+              None
+            }
+          }
+        })
+        .collect()
+    }
+
+    /// Removes every non-root `ChildrenNode` that has exactly one child, moving the child into
+    /// the removed node's place under its parent, and returns how many nodes were removed. Roots
+    /// are kept even if they have a single child.
+    pub fn collapse_chains(&mut self) -> usize {
+      let candidates: Vec<K> = self
+        .node_map
+        .iter()
+        .filter(|(id, node)| {
+          node.kind() == NodeKind::Children && node.child_count() == 1 && node.parent() != **id
+        })
+        .map(|(id, _)| id.clone())
+        .collect();
+      let mut collapsed = 0;
+      for id in candidates {
+        let parent_id = self.node_map[&id].parent();
+        let child_id = match self.node_map[&id].children().next() {
+          Some(child_id) => child_id,
+          None => continue,
+        };
+        let replaced = match self.node_map.get_mut(&parent_id) {
+          Some(parent) => parent.replace_child_id(id.clone(), child_id.clone()),
+          None => false,
+        };
+        if !replaced {
+          continue;
+        }
+        if let Some(child) = self.node_map.get_mut(&child_id) {
+          child.set_parent(parent_id);
+        }
+        self.take_node(id);
+        collapsed += 1;
+      }
+      collapsed
+    }
+
+    /// Swaps `left` and `right` of the `LeftRightNode` `id`.
+    pub fn mirror_node(&mut self, id: K) -> Result<(), Error<K>> {
+      match self.node_map.get_mut(&id) {
+        Some(Node::LeftRightNode(inner_node)) => {
+          std::mem::swap(&mut inner_node.left, &mut inner_node.right);
+          Ok(())
+        }
+        Some(node) => Err(Error::WrongNodeType {
+          id,
+          expected: "left_right",
+          found: node.kind_name(),
+        }),
+        None => Err(Error::NotFound(id)),
+      }
+    }
+
+    /// Mirrors every `LeftRightNode` in the subtree rooted at `root`, producing its reflection.
+    /// `ChildrenNode`s are walked through but keep their child order.
+    pub fn mirror_subtree(&mut self, root: K) -> Result<(), Error<K>> {
+      if !self.node_map.contains_key(&root) {
+        return Err(Error::NotFound(root));
+      }
+      for id in self.preorder_ids(root) {
+        if let Some(Node::LeftRightNode(inner_node)) = self.node_map.get_mut(&id) {
+          std::mem::swap(&mut inner_node.left, &mut inner_node.right);
+        }
+      }
+      Ok(())
+    }
+
+    /// Panics if the tree is structurally inconsistent: a child id that is not in the tree, a
+    /// node listing itself, a child whose `parent` does not point back, a node listed more than
+    /// once, a non-root node its parent does not list, or parent links that cycle instead of
+    /// reaching a root. Together these mean the roots are exactly the nodes no parent lists.
+    /// Only built with debug assertions.
+    #[cfg(debug_assertions)]
+    pub fn assert_invariants(&self)
+    where
+      K: fmt::Debug,
+    {
+      let mut listed_by: HashMap<K, K> = HashMap::with_capacity(self.node_map.len());
+      for (id, node) in self.node_map.iter() {
+        for child_id in node.children() {
+          let child = match self.node_map.get(&child_id) {
+            Some(child) => child,
+            None => panic!("child {:?} of {:?} is not in the tree", child_id, id),
+          };
+          assert!(child_id != *id, "{:?} lists itself as a child", id);
+          assert!(
+            child.parent() == *id,
+            "child {:?} of {:?} has parent {:?}",
+            child_id,
+            id,
+            child.parent()
+          );
+          if let Some(other) = listed_by.insert(child_id.clone(), id.clone()) {
+            panic!(
+              "{:?} is listed more than once, by {:?} and {:?}",
+              child_id, other, id
+            );
+          }
+        }
+      }
+
+      for (id, node) in self.node_map.iter() {
+        let parent_id = node.parent();
+        assert!(
+          parent_id == *id || listed_by.get(id) == Some(&parent_id),
+          "{:?} has parent {:?}, which does not list it",
+          id,
+          parent_id
+        );
+      }
+      assert!(!self.has_cycle(), "parent links form a cycle");
+    }
+  }
+
+  impl NodeTree {
+    /// Creates a tree that mints new ids with `id_generator` instead of `Uuid::new_v4`, e.g. to
+    /// get deterministic ids in tests. The generator must not repeat ids.
+    pub fn with_id_generator(id_generator: IdGenerator) -> NodeTree {
+      let mut node_tree = NodeTree::new();
+      node_tree.id_generator = Some(id_generator);
+      node_tree
+    }
+
+    /// Mints an id that is not in the tree yet. Generated ids can already be taken, e.g. after
+    /// `compact` renumbered nodes into the generator's range, so taken ids are skipped.
+    fn next_id(&self) -> Uuid {
+      for _ in 0..=self.node_map.len() {
+        let id = match &self.id_generator {
+          Some(id_generator) => id_generator(),
+          None => Uuid::new_v4(),
+        };
+        if !self.node_map.contains_key(&id) {
+          return id;
+        }
+      }
+      panic!("the id generator keeps returning ids that are already in the tree");
+    }
+
+    pub fn make_root(&mut self) -> Uuid {
+      let id = self.next_id();
+      let node = ChildrenNode::new(id, id);
+      self.insert_node(id, node);
+      id
+    }
+
+    pub fn make_root_handle(&mut self) -> NodeHandle {
+      let id = self.make_root();
+      self.handle(id).unwrap()
+    }
+
+    pub fn make_node(&mut self, parent_id: Uuid, factory: NodeFactory) -> Result<Uuid, Error> {
+      let id = self.next_id();
+      self.make_node_with_id(parent_id, id, factory)
+    }
+
+    /// Creates an empty node of `kind` under `parent_id`. It is appended to a `ChildrenNode`
+    /// parent's children, or put in the first free slot of a `LeftRightNode` parent.
+    pub fn add_child(&mut self, parent_id: Uuid, kind: NodeKind) -> Result<Uuid, Error> {
+      let id = self.next_id();
+      self.add_child_with_id(parent_id, id, kind)
+    }
+
+    fn mint_child(&mut self, parent_id: Uuid, factory: &dyn Fn(Uuid) -> Node) -> Uuid {
+      let id = self.next_id();
+      let mut node = factory(id);
+      node.set_parent(parent_id);
+      self.insert_node(id, node);
+      id
+    }
+
+    pub fn make_node_handle(
+      &mut self,
+      parent_id: Uuid,
+      factory: NodeFactory,
+    ) -> Result<NodeHandle, Error> {
+      let id = self.make_node(parent_id, factory)?;
+      Ok(self.handle(id).unwrap())
+    }
+
+    /// Reassigns every node a sequential id (`Uuid::from_u128(1)`, `2`, ...) in depth-first
+    /// pre-order, starting from the roots in ascending order of their current ids, and returns the
+    /// old to new id mapping. Nodes unreachable from any root are numbered last, by current id.
+    /// Two trees of the same shape end up with identical ids regardless of how they were built,
+    /// which makes their contents directly comparable. Existing handles become stale.
+    pub fn compact(&mut self) -> HashMap<Uuid, Uuid> {
+      let mut roots: Vec<Uuid> = self
+        .node_map
+        .values()
+        .filter(|node| node.parent() == node.id())
+        .map(Node::id)
+        .collect();
+      roots.sort();
+      let mut mapping: HashMap<Uuid, Uuid> = HashMap::with_capacity(self.node_map.len());
+      for root in roots {
+        let mut stack = vec![root];
+        while let Some(id) = stack.pop() {
+          if mapping.contains_key(&id) {
+            continue;
+          }
+          if let Some(node) = self.node_map.get(&id) {
+            mapping.insert(id, Uuid::from_u128(mapping.len() as u128 + 1));
+            let children: Vec<Uuid> = node.children().collect();
+            stack.extend(children.into_iter().rev());
+          }
+        }
+      }
+      let mut unreachable: Vec<Uuid> = self
+        .node_map
+        .keys()
+        .filter(|id| !mapping.contains_key(id))
+        .copied()
+        .collect();
+      unreachable.sort();
+      for id in unreachable {
+        let next_id = Uuid::from_u128(mapping.len() as u128 + 1);
+        mapping.insert(id, next_id);
+      }
+
+      self.attachments = std::mem::take(&mut self.attachments)
+        .into_iter()
+        .map(|(id, attachments)| (mapping[&id], attachments))
+        .collect();
+      self.expanded = self.expanded.iter().map(|id| mapping[id]).collect();
+      let old_map = std::mem::take(&mut self.node_map);
+      for (_, mut node) in old_map {
+        node.remap_ids(&mapping);
+        self.insert_node(node.id(), node);
+      }
+      mapping
+    }
+
+    /// Creates whichever children of the `LeftRightNode` `id` are missing, leaving occupied slots
+    /// untouched, and returns the `(left, right)` ids. The factories receive the new child's id.
+    pub fn ensure_children(
+      &mut self,
+      id: Uuid,
+      make_left: Box<dyn Fn(Uuid) -> Node>,
+      make_right: Box<dyn Fn(Uuid) -> Node>,
+    ) -> Result<(Uuid, Uuid), Error> {
+      let (left, right) = match self.node_map.get(&id) {
+        Some(Node::LeftRightNode(inner_node)) => (inner_node.left, inner_node.right),
+        Some(node) => {
+          return Err(Error::WrongNodeType {
+            id,
+            expected: "left_right",
+            found: node.kind_name(),
+          })
+        }
+        None => return Err(Error::NotFound(id)),
+      };
+      let left = match left {
+        Some(left) => left,
+        None => self.mint_child(id, &*make_left),
+      };
+      let right = match right {
+        Some(right) => right,
+        None => self.mint_child(id, &*make_right),
+      };
+      let inner_node = self.node_map.get_mut(&id).unwrap().expect_left_right_mut();
+      inner_node.left = Some(left);
+      inner_node.right = Some(right);
+      Ok((left, right))
+    }
+
+    pub fn add_children_child(&mut self, parent_id: Uuid) -> Result<ChildrenId, Error> {
+      self
+        .add_child(parent_id, NodeKind::Children)
+        .map(ChildrenId)
+    }
+
+    pub fn add_left_right_child(&mut self, parent_id: Uuid) -> Result<LeftRightId, Error> {
+      self
+        .add_child(parent_id, NodeKind::LeftRight)
+        .map(LeftRightId)
+    }
+
+    /// Creates a `LeftRightNode` in the empty left slot of `parent_id`.
+    pub fn set_left(&mut self, parent_id: LeftRightId) -> Result<LeftRightId, Error> {
+      self.set_slot(parent_id, true)
+    }
+
+    /// Creates a `LeftRightNode` in the empty right slot of `parent_id`.
+    pub fn set_right(&mut self, parent_id: LeftRightId) -> Result<LeftRightId, Error> {
+      self.set_slot(parent_id, false)
+    }
+
+    fn set_slot(&mut self, parent_id: LeftRightId, left: bool) -> Result<LeftRightId, Error> {
+      let id = self.next_id();
+      let parent = match self.node_map.get_mut(&parent_id) {
+        Some(Node::LeftRightNode(inner_node)) => inner_node,
+        Some(node) => {
+          return Err(Error::WrongNodeType {
+            id: *parent_id,
+            expected: "left_right",
+            found: node.kind_name(),
+          })
+        }
+        None => return Err(Error::NotFound(*parent_id)),
+      };
+      let slot = if left {
+        &mut parent.left
+      } else {
+        &mut parent.right
+      };
+      if slot.is_some() {
+        return Err(Error::NoFreeSlot);
+      }
+      *slot = Some(id);
+      self.insert_node(id, LeftRightNode::new(id, *parent_id));
+      Ok(LeftRightId(id))
+    }
+
+    /// Returns `None` if the node is gone, or if its id has since been reused for a
+    /// `LeftRightNode` (e.g. after `compact`).
+    pub fn get_children_node(&self, id: ChildrenId) -> Option<&ChildrenNode> {
+      self.node_map.get(&id).and_then(Node::as_children)
+    }
+
+    /// Returns `None` if the node is gone, or if its id has since been reused for a
+    /// `ChildrenNode`.
+    pub fn get_left_right_node(&self, id: LeftRightId) -> Option<&LeftRightNode> {
+      self.node_map.get(&id).and_then(Node::as_left_right)
+    }
+
+    /// Returns the id of the tree's root, creating one with `make_root` if there is none. In a
+    /// forest, the root with the lowest id is returned.
+    pub fn root(&mut self) -> Uuid {
+      let existing = self
+        .node_map
+        .values()
+        .filter(|node| node.parent() == node.id())
+        .map(Node::id)
+        .min();
+      match existing {
+        Some(id) => id,
+        None => self.make_root(),
+      }
+    }
+
+    /// Inserts a node made by `factory` on the edge between `parent` and its child `child`: the
+    /// new node takes the child's place under `parent`, and `child` becomes its last child.
+    /// Fails with `Error::NotAChild` if `child` is not a child of `parent`.
+    pub fn insert_between(
+      &mut self,
+      parent: Uuid,
+      child: Uuid,
+      factory: Box<dyn Fn(Uuid) -> Node>,
+    ) -> Result<Uuid, Error> {
+      let parent_node = self.node_map.get(&parent).ok_or(Error::NotFound(parent))?;
+      if child == parent || !parent_node.children().any(|child_id| child_id == child) {
+        return Err(Error::NotAChild);
+      }
+      let id = self.next_id();
+      let mut node = factory(id);
+      node.set_parent(parent);
+      node.add_child_id(child)?;
+      self
+        .node_map
+        .get_mut(&parent)
+        .unwrap()
+        .replace_child_id(child, id);
+      if let Some(child_node) = self.node_map.get_mut(&child) {
+        child_node.set_parent(id);
+      }
+      self.insert_node(id, node);
+      Ok(id)
+    }
+
+    /// Writes the subtree rooted at `root` in Newick format, e.g. `(0000000a,0000000b)00000001;`,
+    /// labelling nodes with their `NewickLabel` attachment or else their short id. Labels that are
+    /// empty or contain whitespace or any of `()[],;:'` are single-quoted, with `'` doubled, so
+    /// `from_newick` reads them back unchanged. Returns `None` if `root` is not in the tree.
+    pub fn to_newick(&self, root: Uuid) -> Option<String> {
+      if !self.node_map.contains_key(&root) {
+        return None;
+      }
+      let mut written: HashMap<Uuid, String> = HashMap::new();
+      for id in self.postorder_ids(root) {
+        let node = &self.node_map[&id];
+        let mut text = String::new();
+        if node.child_count() > 0 {
+          let children: Vec<String> = node
+            .children()
+            .filter_map(|child_id| written.remove(&child_id))
+            .collect();
+          text.push('(');
+          text.push_str(&children.join(","));
+          text.push(')');
+        }
+        match self.attachment::<NewickLabel>(id) {
+          Some(NewickLabel(label)) => push_newick_label(&mut text, label),
+          None => text.push_str(&short_id(id)),
+        }
+        written.insert(id, text);
+      }
+      written.remove(&root).map(|text| text + ";")
+    }
+
+    /// Parses a Newick tree such as `((A,B)C,D)E;` into a tree of `ChildrenNode`s and returns it
+    /// with its root. Labels are attached to their nodes as `NewickLabel`s; they may be
+    /// single-quoted, with `''` standing for a quote. Comments and branch lengths are not
+    /// supported.
+    pub fn from_newick(input: &str) -> Result<(NodeTree, Uuid), Error> {
+      let invalid =
+        |position: usize, message: &'static str| Error::InvalidNewick { position, message };
+      let mut node_tree = NodeTree::new();
+      let root_id = node_tree.make_root();
+      let mut current = root_id;
+      let mut open: Vec<Uuid> = Vec::new();
+      let mut labelled = false;
+      let mut chars = input.char_indices().peekable();
+      loop {
+        let (position, c) = match chars.next() {
+          Some(next) => next,
+          None => return Err(invalid(input.len(), "missing closing `;`")),
+        };
+        match c {
+          '(' if labelled || node_tree.node_map[&current].child_count() > 0 => {
+            return Err(invalid(position, "unexpected `(`"))
+          }
+          '(' => {
+            open.push(current);
+            current = node_tree.add_child(current, NodeKind::Children)?;
+          }
+          ',' => match open.last() {
+            Some(parent) => {
+              current = node_tree.add_child(*parent, NodeKind::Children)?;
+              labelled = false;
+            }
+            None => return Err(invalid(position, "`,` outside of parentheses")),
+          },
+          ')' => match open.pop() {
+            Some(parent) => {
+              current = parent;
+              labelled = false;
+            }
+            None => return Err(invalid(position, "unmatched `)`")),
+          },
+          ';' if !open.is_empty() => return Err(invalid(position, "unclosed `(`")),
+          ';' => break,
+          c if c.is_whitespace() => {}
+          ':' => return Err(invalid(position, "branch lengths are not supported")),
+          '\'' => {
+            if labelled {
+              return Err(invalid(position, "unexpected label"));
+            }
+            let mut label = String::new();
+            loop {
+              match chars.next() {
+                Some((_, '\'')) if chars.peek().map(|(_, next)| *next) == Some('\'') => {
+                  chars.next();
+                  label.push('\'');
+                }
+                Some((_, '\'')) => break,
+                Some((_, c)) => label.push(c),
+                None => return Err(invalid(position, "unclosed quote")),
+              }
+            }
+            node_tree.attach(current, NewickLabel(label))?;
+            labelled = true;
+          }
+          c if is_newick_label_char(c) => {
+            if labelled {
+              return Err(invalid(position, "unexpected label"));
+            }
+            let mut end = position + c.len_utf8();
+            while let Some((next_position, next)) = chars.peek().copied() {
+              if !is_newick_label_char(next) {
+                break;
+              }
+              end = next_position + next.len_utf8();
+              chars.next();
+            }
+            node_tree.attach(current, NewickLabel(input[position..end].to_string()))?;
+            labelled = true;
+          }
+          _ => return Err(invalid(position, "unsupported character")),
+        }
+      }
+      if let Some((position, _)) = chars.find(|(_, c)| !c.is_whitespace()) {
+        return Err(invalid(position, "input continues after `;`"));
+      }
+      Ok((node_tree, root_id))
+    }
+
+    /// Moves every node of `other` into this tree, keeping its trees as separate roots, and
+    /// returns the ids of the absorbed roots in ascending order. Nodes whose ids are already
+    /// taken here get freshly minted ids; their attachments move with them.
+    pub fn absorb(&mut self, mut other: NodeTree) -> Vec<Uuid> {
+      let mut mapping = HashMap::new();
+      for id in other.node_map.keys() {
+        if self.node_map.contains_key(id) {
+          let mut new_id = self.next_id();
+          while self.node_map.contains_key(&new_id) || other.node_map.contains_key(&new_id) {
+            new_id = self.next_id();
+          }
+          mapping.insert(*id, new_id);
+        }
+      }
+      let remap = |id: Uuid| *mapping.get(&id).unwrap_or(&id);
+      let mut roots = Vec::new();
+      for (id, mut node) in std::mem::take(&mut other.node_map) {
+        node.remap_ids(&mapping);
+        if node.parent() == node.id() {
+          roots.push(node.id());
+        }
+        self.insert_node(remap(id), node);
+      }
+      for (id, attachments) in std::mem::take(&mut other.attachments) {
+        self.attachments.insert(remap(id), attachments);
+      }
+      self
+        .expanded
+        .extend(other.expanded.iter().map(|id| remap(*id)));
+      roots.sort();
+      roots
+    }
+
+    /// Mints `n` distinct ids that are not in the tree, without inserting anything, to be placed
+    /// later with `make_node_with_id` or `add_child_with_id`.
+    pub fn reserve_ids(&self, n: usize) -> Vec<Uuid> {
+      let mut ids = Vec::with_capacity(n);
+      let mut reserved = HashSet::with_capacity(n);
+      while ids.len() < n {
+        let id = self.next_id();
+        if !self.node_map.contains_key(&id) && reserved.insert(id) {
+          ids.push(id);
+        }
+      }
+      ids
+    }
+
+    /// Builds a tree from a `NestedNode`, minting an id for every node, and returns it with its
+    /// root.
+    pub fn from_nested(nested: NestedNode) -> (NodeTree, Uuid) {
+      let mut node_tree = NodeTree::new();
+      let root_id = node_tree.next_id();
+      let mut queue = VecDeque::new();
+      queue.push_back((nested, root_id, None));
+      while let Some((nested, parent_id, edge)) = queue.pop_front() {
+        let id = if edge.is_none() {
+          root_id
+        } else {
+          node_tree.next_id()
+        };
+        let node = match nested {
+          NestedNode::Children(children) => {
+            queue.extend(
+              children
+                .into_iter()
+                .enumerate()
+                .map(|(index, child)| (child, id, Some(EdgeKind::Child(index)))),
+            );
+            ChildrenNode::new(id, parent_id)
+          }
+          NestedNode::LeftRight(left, right) => {
+            queue.extend(left.map(|left| (*left, id, Some(EdgeKind::Left))));
+            queue.extend(right.map(|right| (*right, id, Some(EdgeKind::Right))));
+            LeftRightNode::new(id, parent_id)
+          }
+        };
+        match (node_tree.node_map.get_mut(&parent_id), edge) {
+          (Some(Node::ChildrenNode(parent)), Some(EdgeKind::Child(_))) => parent.push_child(id),
+          (Some(Node::LeftRightNode(parent)), Some(EdgeKind::Left)) => parent.left = Some(id),
+          (Some(Node::LeftRightNode(parent)), Some(EdgeKind::Right)) => parent.right = Some(id),
+          _ => {}
+        }
+        node_tree.insert_node(id, node);
+      }
+      (node_tree, root_id)
+    }
+
+    /// Builds a complete tree of `ChildrenNode`s where every node above `depth` has `branching`
+    /// children, returning the tree and its root. A `depth` of 0 yields a lone root; in general
+    /// the tree holds `(branching^(depth + 1) - 1) / (branching - 1)` nodes, or `depth + 1` when
+    /// `branching` is 1.
+    pub fn gen_balanced(depth: usize, branching: usize) -> (NodeTree, Uuid) {
+      let mut node_tree = NodeTree::new();
+      let root_id = node_tree.make_root();
+      let mut level = vec![root_id];
+      for _ in 0..depth {
+        let mut next_level = Vec::with_capacity(level.len() * branching);
+        for parent_id in level {
+          for _ in 0..branching {
+            next_level.push(node_tree.add_child(parent_id, NodeKind::Children).unwrap());
+          }
+        }
+        level = next_level;
+      }
+      (node_tree, root_id)
+    }
+
+    /// The short ids from the root down to `id` joined by `/`, e.g. `ab12cd34/ef56ab78`. Short
+    /// ids are only the first 8 hex digits, so this is for logs and display rather than lookups.
+    /// Returns `None` if `id` is not in the tree.
+    pub fn path_string(&self, id: Uuid) -> Option<String> {
+      let mut path: Vec<String> = self.ancestors_inclusive(id).map(short_id).collect();
+      if path.is_empty() {
+        return None;
+      }
+      path.reverse();
+      Some(path.join("/"))
+    }
+  }
+
+  impl<K: Key> Default for GenericTree<K> {
+    fn default() -> Self {
+      GenericTree::new()
+    }
+  }
+
+  /// A subtree as a self-contained recursive value, without ids. See `GenericTree::into_nested`
+  /// and `NodeTree::from_nested`.
+  #[derive(Debug, Clone, PartialEq, Eq)]
+  pub enum NestedNode {
+    Children(Vec<NestedNode>),
+    LeftRight(Option<Box<NestedNode>>, Option<Box<NestedNode>>),
+  }
+
+  /// A view into a single id of a tree, which may or may not hold a node, see
+  /// `GenericTree::entry`.
+  pub enum Entry<'a, K = Uuid> {
+    Occupied(OccupiedEntry<'a, K>),
+    Vacant(VacantEntry<'a, K>),
+  }
+
+  pub struct OccupiedEntry<'a, K = Uuid> {
+    id: K,
+    tree: &'a mut GenericTree<K>,
+  }
+
+  pub struct VacantEntry<'a, K = Uuid> {
+    id: K,
+    tree: &'a mut GenericTree<K>,
+  }
+
+  impl<'a, K: Key> Entry<'a, K> {
+    pub fn id(&self) -> &K {
+      match self {
+        Entry::Occupied(entry) => &entry.id,
+        Entry::Vacant(entry) => &entry.id,
+      }
+    }
+
+    /// Returns the existing node, or creates it under `parent_id` with `factory` like
+    /// `make_node_with_id`.
+    pub fn or_insert_with(
+      self,
+      parent_id: K,
+      factory: NodeFactory<K>,
+    ) -> Result<&'a mut Node<K>, Error<K>> {
+      match self {
+        Entry::Occupied(entry) => Ok(entry.into_mut()),
+        Entry::Vacant(entry) => entry.insert(parent_id, factory),
+      }
+    }
+  }
+
+  impl<'a, K: Key> OccupiedEntry<'a, K> {
+    pub fn id(&self) -> &K {
+      &self.id
+    }
+
+    pub fn get(&self) -> &Node<K> {
+      &self.tree.node_map[&self.id]
+    }
+
+    pub fn get_mut(&mut self) -> &mut Node<K> {
+      self.tree.node_map.get_mut(&self.id).unwrap()
+    }
+
+    pub fn into_mut(self) -> &'a mut Node<K> {
+      self.tree.node_map.get_mut(&self.id).unwrap()
+    }
+  }
+
+  impl<'a, K: Key> VacantEntry<'a, K> {
+    pub fn id(&self) -> &K {
+      &self.id
+    }
+
+    /// Creates the node under `parent_id` with `factory`, like `make_node_with_id`.
+    pub fn insert(
+      self,
+      parent_id: K,
+      factory: NodeFactory<K>,
+    ) -> Result<&'a mut Node<K>, Error<K>> {
+      let id = self.tree.make_node_with_id(parent_id, self.id, factory)?;
+      Ok(self.tree.node_map.get_mut(&id).unwrap())
+    }
+  }
+
+  /// A read-only view of a node that keeps a reference to its tree, so related nodes can be
+  /// reached without passing the tree around.
+  #[derive(Clone, Copy)]
+  pub struct NodeRef<'a, K = Uuid> {
+    id: K,
+    node: &'a Node<K>,
+    tree: &'a GenericTree<K>,
+  }
+
+  impl<'a, K: Key> NodeRef<'a, K> {
+    pub fn id(&self) -> K {
+      self.id.clone()
+    }
+
+    pub fn node(&self) -> &'a Node<K> {
+      self.node
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = NodeRef<'a, K>> + 'a {
+      let tree = self.tree;
+      self.node.children().filter_map(move |id| tree.node_ref(id))
+    }
+
+    /// Returns `None` for roots.
+    pub fn parent(&self) -> Option<NodeRef<'a, K>> {
+      if self.node.parent() == self.id {
+        None
+      } else {
+        self.tree.node_ref(self.node.parent())
+      }
+    }
+
+    /// Number of edges between this node and its root. Bounded like `GenericTree::ancestors`, so
+    /// it terminates even if parent links form a cycle.
+    pub fn depth(&self) -> usize {
+      self.tree.ancestors(self.id.clone()).count()
+    }
+  }
+
+  impl<'a, K: Key + fmt::Debug> fmt::Debug for NodeRef<'a, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      f.debug_struct("NodeRef")
+        .field("id", &self.id)
+        .field("node", self.node)
+        .finish()
+    }
+  }
+
+  pub const EXPLAINED_DIFFERENCES_LIMIT: usize = 5;
+
+  fn count_children(count: usize) -> String {
+    if count == 1 {
+      "1 child".to_string()
+    } else {
+      format!("{} children", count)
+    }
+  }
+
+  fn describe<K: Key>(node: &Node<K>) -> String {
+    match node {
+      Node::ChildrenNode(inner_node) => {
+        format!(
+          "ChildrenNode with {}",
+          count_children(inner_node.children.len())
+        )
+      }
+      Node::LeftRightNode(_) => {
+        format!("LeftRightNode with {}", count_children(node.child_count()))
+      }
+    }
+  }
+
+  fn is_newick_label_char(c: char) -> bool {
+    !"()[],;:'".contains(c) && !c.is_whitespace()
+  }
+
+  fn push_newick_label(text: &mut String, label: &str) {
+    if !label.is_empty() && label.chars().all(is_newick_label_char) {
+      text.push_str(label);
+    } else {
+      text.push('\'');
+      text.push_str(&label.replace('\'', "''"));
+      text.push('\'');
+    }
+  }
+
+  fn short_id(id: Uuid) -> String {
+    id.to_simple().to_string()[..8].to_string()
+  }
+
+  /// `short_id` for `Uuid` keys, `{:?}` for any other key type.
+  fn short_key<K: fmt::Debug + 'static>(id: &K) -> String {
+    match (id as &dyn Any).downcast_ref::<Uuid>() {
+      Some(id) => short_id(*id),
+      None => format!("{:?}", id),
+    }
+  }
+
+  fn short_keys<K: fmt::Debug + 'static>(ids: impl Iterator<Item = K>) -> String {
+    ids.map(|id| short_key(&id)).collect::<Vec<_>>().join(", ")
+  }
+
+  /// A one-line summary with short ids, e.g. `ChildrenNode(ab12cd34, 2 children)` or
+  /// `LeftRightNode(ef56ab78, L=12cd34ef R=none)`. Keys other than `Uuid` are printed with `{:?}`.
+  /// Use `{:?}` for every field.
+  impl<K: Key + fmt::Debug + 'static> fmt::Display for Node<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      match self {
+        Node::ChildrenNode(inner_node) => write!(
+          f,
+          "ChildrenNode({}, {})",
+          short_key(&inner_node.id),
+          count_children(inner_node.children.len())
+        ),
+        Node::LeftRightNode(inner_node) => {
+          let slot = |slot: &Option<K>| slot.as_ref().map_or("none".to_string(), short_key);
+          write!(
+            f,
+            "LeftRightNode({}, L={} R={})",
+            short_key(&inner_node.id),
+            slot(&inner_node.left),
+            slot(&inner_node.right)
+          )
+        }
+      }
+    }
+  }
+
+  /// `{:?}` lists the nodes sorted by id, one per line, with their kind, parent and children.
+  /// `{:#?}` prints the indented structure below each root instead. Either way, at most
+  /// `debug_limit` nodes are printed (see `set_debug_limit`). Ids are shortened for `Uuid` keys
+  /// and printed with `{:?}` otherwise, which is also the order they are sorted in.
+  impl<K: Key + fmt::Debug + 'static> fmt::Debug for GenericTree<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+      let sort_key = |id: &K| format!("{:?}", id);
+      let mut roots: Vec<K> = self
+        .node_map
+        .values()
+        .filter(|node| node.parent() == node.id())
+        .map(Node::id)
+        .collect();
+      roots.sort_by_cached_key(sort_key);
+      let name = if TypeId::of::<K>() == TypeId::of::<Uuid>() {
+        "NodeTree"
+      } else {
+        "GenericTree"
+      };
+      write!(
+        f,
+        "{} ({} nodes, roots: [{}])",
+        name,
+        self.node_map.len(),
+        short_keys(roots.iter().cloned())
+      )?;
+
+      let mut printed = 0;
+      if f.alternate() {
+        let mut stack: Vec<(K, usize)> = roots.into_iter().rev().map(|id| (id, 0)).collect();
+        while let Some((id, depth)) = stack.pop() {
+          if printed == self.debug_limit {
+            break;
+          }
+          if let Some(node) = self.node_map.get(&id) {
+            write!(
+              f,
+              "\n{}{} {}",
+              "  ".repeat(depth + 1),
+              short_key(&id),
+              node.kind_name()
+            )?;
+            printed += 1;
+            let children: Vec<K> = node.children().collect();
+            stack.extend(
+              children
+                .into_iter()
+                .rev()
+                .map(|child_id| (child_id, depth + 1)),
+            );
+          }
+        }
+      } else {
+        let mut nodes: Vec<&Node<K>> = self.node_map.values().collect();
+        nodes.sort_by_cached_key(|node| sort_key(&node.id()));
+        for node in nodes.into_iter().take(self.debug_limit) {
+          write!(
+            f,
+            "\n  {} {} parent={} children=[{}]",
+            short_key(&node.id()),
+            node.kind_name(),
+            short_key(&node.parent()),
+            short_keys(node.children())
+          )?;
+          printed += 1;
+        }
+      }
+      if printed < self.node_map.len() {
+        write!(f, "\n  … {} more", self.node_map.len() - printed)?;
+      }
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::tree::{
+    ChildrenId, ChildrenNode, EdgeKind, Entry, Error, GenericTree, LeftRightId, LeftRightNode,
+    NestedNode, NewickLabel, Node, NodeFactory, NodeKind, NodeTree, TreeNode,
+    EXPLAINED_DIFFERENCES_LIMIT,
+  };
+  use std::cell::Cell;
+  use std::collections::HashSet;
+  use std::sync::atomic::{AtomicU64, Ordering};
+  use uuid::Uuid;
+
+  fn sequential_tree() -> NodeTree {
+    let counter = AtomicU64::new(0);
+    NodeTree::with_id_generator(Box::new(move || {
+      let n = counter.fetch_add(1, Ordering::Relaxed) + 1;
+      Uuid::from_u128(u128::from(n) << 96)
+    }))
+  }
+
+  fn attach_child(parent: &mut Node, id: Uuid) -> Uuid {
+    parent.add_child_id(id).unwrap();
+    parent.id()
+  }
+
+  fn add_children_node(node_tree: &mut NodeTree, parent_id: Uuid) -> Uuid {
+    node_tree
+      .make_node(
+        parent_id,
+        Box::new(|id, parent| ChildrenNode::new(id, attach_child(parent, id))),
+      )
+      .unwrap()
+  }
+
+  fn add_left_right_node(node_tree: &mut NodeTree, parent_id: Uuid) -> Uuid {
+    node_tree
+      .make_node(
+        parent_id,
+        Box::new(|id, parent| LeftRightNode::new(id, attach_child(parent, id))),
+      )
+      .unwrap()
+  }
+
+  #[test]
+  fn can_create_node() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let child_1_id = node_tree.make_node(
+      root_id,
+      Box::new(move |id, parent| {
+        let parent_inner = parent.as_children_mut().unwrap();
+        parent_inner.push_child(id);
+        LeftRightNode::new(id, parent_inner.id)
+      }),
+    );
+    let child_2_id = node_tree.make_node(
+      root_id,
+      Box::new(move |id, parent| {
+        let parent_inner = parent.as_children_mut().unwrap();
+        parent_inner.push_child(id);
+        LeftRightNode::new(id, parent_inner.id)
+      }),
+    );
+    println!(
+      "root_id={:?} child_1_id={:?} child_2_id={:?}",
+      root_id, child_1_id, child_2_id
+    );
+    println!("root_node {:?}", node_tree.get_node(root_id))
+  }
+
+  #[test]
+  fn iter_with_parent_yields_parent_nodes() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let child_id = add_children_node(&mut node_tree, root_id);
+    let grandchild_id = add_children_node(&mut node_tree, child_id);
+
+    let mut seen = 0;
+    for (id, parent) in node_tree.iter_with_parent() {
+      let parent_id = parent.map(|node| node.expect_children().id);
+      if id == root_id {
+        assert_eq!(parent_id, None);
+      } else if id == child_id {
+        assert_eq!(parent_id, Some(root_id));
+      } else if id == grandchild_id {
+        assert_eq!(parent_id, Some(child_id));
+      }
+      seen += 1;
+    }
+    assert_eq!(seen, 3);
+  }
+
+  #[test]
+  fn levels_groups_nodes_by_depth() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = add_children_node(&mut node_tree, root_id);
+    let b = add_left_right_node(&mut node_tree, root_id);
+    let a_1 = add_children_node(&mut node_tree, a);
+    let b_left = add_children_node(&mut node_tree, b);
+    let b_right = add_children_node(&mut node_tree, b);
+    let a_1_1 = add_children_node(&mut node_tree, a_1);
+
+    assert_eq!(
+      node_tree.levels(root_id).unwrap(),
+      vec![
+        vec![root_id],
+        vec![a, b],
+        vec![a_1, b_left, b_right],
+        vec![a_1_1]
+      ]
+    );
+    assert_eq!(
+      node_tree.levels(a).unwrap(),
+      vec![vec![a], vec![a_1], vec![a_1_1]]
+    );
+    assert_eq!(node_tree.nodes_at_depth(root_id, 0).unwrap(), vec![root_id]);
+    assert_eq!(
+      node_tree.nodes_at_depth(root_id, 2).unwrap(),
+      vec![a_1, b_left, b_right]
+    );
+    assert!(node_tree.nodes_at_depth(root_id, 10).unwrap().is_empty());
+    assert!(node_tree.levels(Uuid::new_v4()).is_err());
+    assert!(node_tree.nodes_at_depth(Uuid::new_v4(), 0).is_err());
+  }
+
+  #[test]
+  fn trim_to_depth_removes_deeper_nodes() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = add_children_node(&mut node_tree, root_id);
+    let b = add_left_right_node(&mut node_tree, root_id);
+    let a_1 = add_children_node(&mut node_tree, a);
+    let b_left = add_children_node(&mut node_tree, b);
+    let a_1_1 = add_children_node(&mut node_tree, a_1);
+
+    assert_eq!(node_tree.trim_to_depth(root_id, 1), 3);
+    assert_eq!(
+      node_tree.levels(root_id).unwrap(),
+      vec![vec![root_id], vec![a, b]]
+    );
+    assert!(node_tree.get_node(a_1).is_none());
+    assert!(node_tree.get_node(b_left).is_none());
+    assert!(node_tree.get_node(a_1_1).is_none());
+    let b_node = node_tree.get_node(b).unwrap().expect_left_right();
+    assert_eq!((b_node.left, b_node.right), (None, None));
+    assert_eq!(node_tree.trim_to_depth(root_id, 5), 0);
+    assert_eq!(node_tree.trim_to_depth(Uuid::new_v4(), 0), 0);
+  }
+
+  #[test]
+  fn children_accessor_is_uniform_across_variants() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = add_children_node(&mut node_tree, root_id);
+    let b = add_children_node(&mut node_tree, root_id);
+    let root = node_tree.get_node(root_id).unwrap();
+    assert_eq!(root.children().collect::<Vec<_>>(), vec![a, b]);
+    assert_eq!(root.child_count(), 2);
+    assert_eq!(root.id(), root_id);
+    assert_eq!(root.parent(), root_id);
+
+    let mut right_only = LeftRightNode::new_inner(Uuid::new_v4(), root_id);
+    right_only.right = Some(b);
+    let right_only = Node::LeftRightNode(right_only);
+    assert_eq!(right_only.children().collect::<Vec<_>>(), vec![b]);
+    assert_eq!(right_only.child_count(), 1);
+    assert_eq!(right_only.parent(), root_id);
+  }
+
+  #[test]
+  fn handles_go_stale_after_removal() {
+    let mut node_tree = NodeTree::new();
+    let root = node_tree.make_root_handle();
+    let child = node_tree
+      .make_node_handle(
+        root.id(),
+        Box::new(|id, parent| ChildrenNode::new(id, attach_child(parent, id))),
+      )
+      .unwrap();
+    assert_eq!(node_tree.get_by_handle(root).unwrap().id(), root.id());
+    assert_eq!(node_tree.get_by_handle(child).unwrap().id(), child.id());
+    assert_eq!(node_tree.handle(child.id()), Some(child));
+
+    node_tree.trim_to_depth(root.id(), 0);
+    assert!(node_tree.get_by_handle(child).is_none());
+    assert!(node_tree.handle(child.id()).is_none());
+    assert!(node_tree.get_by_handle(root).is_some());
+  }
+
+  fn count_subtree<N: TreeNode>(node_tree: &NodeTree, node: &N) -> usize {
+    1 + node
+      .child_ids()
+      .into_iter()
+      .map(|id| count_subtree(node_tree, node_tree.get_node(id).unwrap()))
+      .sum::<usize>()
+  }
+
+  #[test]
+  fn tree_node_trait_composes_over_node_kinds() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = add_left_right_node(&mut node_tree, root_id);
+    add_children_node(&mut node_tree, a);
+    add_children_node(&mut node_tree, a);
+    assert_eq!(
+      count_subtree(&node_tree, node_tree.get_node(root_id).unwrap()),
+      4
+    );
+
+    let a_inner = node_tree.get_node(a).unwrap().expect_left_right();
+    assert_eq!(count_subtree(&node_tree, a_inner), 3);
+
+    let mut full = LeftRightNode::new_inner(Uuid::new_v4(), root_id);
+    full.add_child_id(Uuid::new_v4()).unwrap();
+    full.add_child_id(Uuid::new_v4()).unwrap();
+    assert!(matches!(
+      full.add_child_id(Uuid::new_v4()),
+      Err(Error::NoFreeSlot)
+    ));
+    let left = full.left.unwrap();
+    assert!(full.remove_child_id(left));
+    assert!(!full.remove_child_id(left));
+    assert_eq!(full.child_ids(), vec![full.right.unwrap()]);
+
+    let mut children = ChildrenNode::new_inner(Uuid::new_v4(), root_id);
+    children.set_parent(a);
+    assert_eq!(TreeNode::parent(&children), a);
+  }
+
+  fn dump(node_tree: &NodeTree) -> Vec<String> {
+    let mut nodes: Vec<_> = node_tree.iter().collect();
+    nodes.sort_by_key(|(id, _)| *id);
+    nodes
+      .into_iter()
+      .map(|(_, node)| format!("{:?}", node))
+      .collect()
+  }
+
+  #[test]
+  fn compact_makes_equal_shapes_identical() {
+    let mut first = NodeTree::new();
+    let first_root = first.make_root();
+    let a = add_children_node(&mut first, first_root);
+    let b = add_left_right_node(&mut first, first_root);
+    add_children_node(&mut first, a);
+    add_children_node(&mut first, b);
+
+    let mut second = NodeTree::new();
+    let second_root = second.make_root();
+    let a = add_children_node(&mut second, second_root);
+    let b = add_left_right_node(&mut second, second_root);
+    add_children_node(&mut second, b);
+    add_children_node(&mut second, a);
+
+    assert_ne!(dump(&first), dump(&second));
+    let mapping = first.compact();
+    second.compact();
+    assert_eq!(dump(&first), dump(&second));
+    assert_eq!(mapping.len(), 5);
+    assert_eq!(mapping[&first_root], Uuid::from_u128(1));
+    let new_root = first.get_node(Uuid::from_u128(1)).unwrap();
+    assert_eq!(new_root.parent(), Uuid::from_u128(1));
+    assert_eq!(
+      new_root.children().collect::<Vec<_>>(),
+      vec![Uuid::from_u128(2), Uuid::from_u128(4)]
+    );
+  }
+
+  #[test]
+  fn typed_accessors_match_on_variant() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let child_id = add_left_right_node(&mut node_tree, root_id);
+    let root = node_tree.get_node(root_id).unwrap();
+    assert_eq!(root.as_children().unwrap().children_slice(), [child_id]);
+    assert!(root.as_left_right().is_none());
+    let child = node_tree.get_node_mut(child_id).unwrap();
+    assert!(child.as_children_mut().is_none());
+    child.as_left_right_mut().unwrap().left = Some(root_id);
+    assert_eq!(child.expect_left_right().left, Some(root_id));
+  }
+
+  #[test]
+  #[should_panic(expected = "to be a ChildrenNode, found a LeftRightNode")]
+  fn expect_children_panics_on_left_right_node() {
+    let node = LeftRightNode::new(Uuid::new_v4(), Uuid::new_v4());
+    node.expect_children();
+  }
+
+  #[test]
+  fn count_where_counts_matching_descendants() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = add_left_right_node(&mut node_tree, root_id);
+    add_children_node(&mut node_tree, a);
+    add_children_node(&mut node_tree, a);
+    add_children_node(&mut node_tree, root_id);
+
+    assert_eq!(
+      node_tree.count_where(root_id, |node| node.child_count() == 0),
+      3
+    );
+    assert_eq!(
+      node_tree.count_where(a, |node| node.as_left_right().is_some()),
+      1
+    );
+    assert_eq!(node_tree.count_where(a, |_| true), 3);
+    assert_eq!(node_tree.count_where(Uuid::new_v4(), |_| true), 0);
+  }
+
+  #[test]
+  fn nodes_compare_by_identity() {
+    let id = Uuid::new_v4();
+    let parent_id = Uuid::new_v4();
+    let mut with_child = ChildrenNode::new_inner(id, parent_id);
+    with_child.push_child(Uuid::new_v4());
+    assert_ne!(with_child, ChildrenNode::new_inner(id, parent_id));
+    assert_eq!(
+      Node::ChildrenNode(with_child),
+      Node::LeftRightNode(LeftRightNode::new_inner(id, id))
+    );
+    assert_ne!(ChildrenNode::new(id, id), ChildrenNode::new(parent_id, id));
+
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = add_children_node(&mut node_tree, root_id);
+    add_children_node(&mut node_tree, a);
+    let mut visited: HashSet<&Node> = HashSet::new();
+    let mut stack = vec![root_id, a, root_id];
+    while let Some(id) = stack.pop() {
+      let node = node_tree.get_node(id).unwrap();
+      if visited.insert(node) {
+        stack.extend(node.children());
+      }
+    }
+    assert_eq!(visited.len(), 3);
+  }
+
+  #[test]
+  fn ensure_children_fills_only_empty_slots() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = add_left_right_node(&mut node_tree, root_id);
+    let existing_left = add_children_node(&mut node_tree, a);
+
+    let (left, right) = node_tree
+      .ensure_children(
+        a,
+        Box::new(|id| ChildrenNode::new(id, id)),
+        Box::new(|id| LeftRightNode::new(id, id)),
+      )
+      .unwrap();
+    assert_eq!(left, existing_left);
+    assert_eq!(node_tree.get_node(right).unwrap().parent(), a);
+    assert!(node_tree.get_node(right).unwrap().as_left_right().is_some());
+    assert_eq!(node_tree.count_where(a, |_| true), 3);
+
+    let again = node_tree
+      .ensure_children(
+        a,
+        Box::new(|id| ChildrenNode::new(id, id)),
+        Box::new(|id| ChildrenNode::new(id, id)),
+      )
+      .unwrap();
+    assert_eq!(again, (left, right));
+    assert_eq!(node_tree.count_where(a, |_| true), 3);
+
+    let result = node_tree.ensure_children(
+      root_id,
+      Box::new(|id| ChildrenNode::new(id, id)),
+      Box::new(|id| ChildrenNode::new(id, id)),
+    );
+    assert!(matches!(result, Err(Error::WrongNodeType { .. })));
+  }
+
+  #[test]
+  fn debug_output_is_readable() {
+    let mut node_tree = sequential_tree();
+    let root_id = node_tree.make_root();
+    let a = add_left_right_node(&mut node_tree, root_id);
+    add_children_node(&mut node_tree, a);
+    add_children_node(&mut node_tree, root_id);
+
+    assert_eq!(
+      format!("{:?}", node_tree),
+      "NodeTree (4 nodes, roots: [00000001])
+  00000001 children parent=00000001 children=[00000002, 00000004]
+  00000002 left_right parent=00000001 children=[00000003]
+  00000003 children parent=00000002 children=[]
+  00000004 children parent=00000001 children=[]"
+    );
+    assert_eq!(
+      format!("{:#?}", node_tree),
+      "NodeTree (4 nodes, roots: [00000001])
+  00000001 children
+    00000002 left_right
+      00000003 children
+    00000004 children"
+    );
+
+    node_tree.set_debug_limit(2);
+    assert_eq!(
+      format!("{:?}", node_tree),
+      "NodeTree (4 nodes, roots: [00000001])
+  00000001 children parent=00000001 children=[00000002, 00000004]
+  00000002 left_right parent=00000001 children=[00000003]
+  … 2 more"
+    );
+    assert_eq!(
+      format!("{:#?}", node_tree),
+      "NodeTree (4 nodes, roots: [00000001])
+  00000001 children
+    00000002 left_right
+  … 2 more"
+    );
+  }
+
+  #[test]
+  fn node_ref_navigates_the_tree() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = add_left_right_node(&mut node_tree, root_id);
+    let a_1 = add_children_node(&mut node_tree, a);
+    let b = add_children_node(&mut node_tree, root_id);
+
+    let root = node_tree.node_ref(root_id).unwrap();
+    assert!(root.parent().is_none());
+    assert_eq!(root.depth(), 0);
+    assert_eq!(
+      root.children().map(|child| child.id()).collect::<Vec<_>>(),
+      vec![a, b]
+    );
+
+    let a_1_ref = node_tree.node_ref(a_1).unwrap();
+    assert_eq!(a_1_ref.depth(), 2);
+    assert_eq!(a_1_ref.parent().unwrap().id(), a);
+    assert_eq!(a_1_ref.parent().unwrap().node().child_count(), 1);
+    assert!(node_tree.node_ref(Uuid::new_v4()).is_none());
+  }
+
+  #[test]
+  fn count_by_kind_on_mixed_tree() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let a_left = node_tree.add_child(a, NodeKind::Children).unwrap();
+    let a_right = node_tree.add_child(a, NodeKind::LeftRight).unwrap();
+    assert!(matches!(
+      node_tree.add_child(a, NodeKind::Children),
+      Err(Error::NoFreeSlot)
+    ));
+    node_tree.add_child(a_left, NodeKind::Children).unwrap();
+
+    let counts = node_tree.count_by_kind();
+    assert_eq!(counts[&NodeKind::Children], 3);
+    assert_eq!(counts[&NodeKind::LeftRight], 2);
+    assert_eq!(counts.len(), 2);
+    assert_eq!(node_tree.kind_of(a_right), Some(NodeKind::LeftRight));
+    assert_eq!(node_tree.kind_of(root_id), Some(NodeKind::Children));
+    assert_eq!(node_tree.kind_of(Uuid::new_v4()), None);
+    assert_eq!(node_tree.get_node(a_left).unwrap().parent(), a);
+    assert_eq!(node_tree.get_node(a).unwrap().kind(), NodeKind::LeftRight);
+  }
+
+  #[test]
+  fn promote_swaps_node_with_parent() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let before = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let parent = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let after = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let sibling = node_tree.add_child(parent, NodeKind::Children).unwrap();
+    let id = node_tree.add_child(parent, NodeKind::Children).unwrap();
+    let grandchild = node_tree.add_child(id, NodeKind::Children).unwrap();
+
+    node_tree.promote(id).unwrap();
+    let root = node_tree.get_node(root_id).unwrap();
+    assert_eq!(root.children().collect::<Vec<_>>(), vec![before, id, after]);
+    let node = node_tree.get_node(id).unwrap();
+    assert_eq!(node.parent(), root_id);
+    assert_eq!(
+      node.children().collect::<Vec<_>>(),
+      vec![grandchild, parent]
+    );
+    let former_parent = node_tree.get_node(parent).unwrap();
+    assert_eq!(former_parent.parent(), id);
+    assert_eq!(former_parent.children().collect::<Vec<_>>(), vec![sibling]);
+
+    assert!(matches!(
+      node_tree.promote(root_id),
+      Err(Error::NoGrandparent)
+    ));
+    assert!(matches!(node_tree.promote(id), Err(Error::NoGrandparent)));
+    assert!(matches!(
+      node_tree.promote(Uuid::new_v4()),
+      Err(Error::NotFound(_))
+    ));
+  }
+
+  #[test]
+  fn typed_ids_check_kind_on_conversion() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let container = node_tree.add_children_child(root_id).unwrap();
+    let binary = node_tree.add_left_right_child(*container).unwrap();
+    let left = node_tree.set_left(binary).unwrap();
+    let right = node_tree.set_right(binary).unwrap();
+    assert!(matches!(node_tree.set_left(binary), Err(Error::NoFreeSlot)));
+
+    let binary_node = node_tree.get_left_right_node(binary).unwrap();
+    assert_eq!(
+      (binary_node.left, binary_node.right),
+      (Some(*left), Some(*right))
+    );
+    assert_eq!(node_tree.get_left_right_node(left).unwrap().parent, *binary);
+    assert_eq!(
+      node_tree
+        .get_children_node(container)
+        .unwrap()
+        .children_slice(),
+      [Uuid::from(binary)]
+    );
+
+    assert_eq!(
+      ChildrenId::try_from_uuid(&node_tree, *container).unwrap(),
+      container
+    );
+    assert!(matches!(
+      ChildrenId::try_from_uuid(&node_tree, *binary),
+      Err(Error::WrongNodeType {
+        expected: "children",
+        found: "left_right",
+        ..
+      })
+    ));
+    assert!(LeftRightId::try_from_uuid(&node_tree, root_id).is_err());
+    assert!(matches!(
+      LeftRightId::try_from_uuid(&node_tree, Uuid::new_v4()),
+      Err(Error::NotFound(_))
+    ));
+  }
+
+  #[test]
+  fn demote_moves_node_under_preceding_sibling() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let first = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let nested = node_tree.add_child(first, NodeKind::Children).unwrap();
+    let id = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let last = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+
+    node_tree.demote(id).unwrap();
+    let root = node_tree.get_node(root_id).unwrap();
+    assert_eq!(root.children().collect::<Vec<_>>(), vec![first, last]);
+    let first_node = node_tree.get_node(first).unwrap();
+    assert_eq!(first_node.children().collect::<Vec<_>>(), vec![nested, id]);
+    assert_eq!(node_tree.get_node(id).unwrap().parent(), first);
+
+    assert!(matches!(
+      node_tree.demote(first),
+      Err(Error::NoPrecedingSibling)
+    ));
+    assert!(matches!(
+      node_tree.demote(nested),
+      Err(Error::NoPrecedingSibling)
+    ));
+    assert!(matches!(
+      node_tree.demote(root_id),
+      Err(Error::NoPrecedingSibling)
+    ));
+    let after_binary = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    assert!(matches!(
+      node_tree.demote(after_binary),
+      Err(Error::WrongNodeType { .. })
+    ));
+  }
+
+  #[test]
+  fn weighted_children_stay_ordered() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let c = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let root = node_tree.get_node(root_id).unwrap().expect_children();
+    assert_eq!(root.weighted_children(), vec![(a, 0), (b, 1), (c, 2)]);
+
+    node_tree.set_child_weight(root_id, a, 1).unwrap();
+    let root = node_tree.get_node(root_id).unwrap();
+    assert_eq!(root.children().collect::<Vec<_>>(), vec![b, a, c]);
+
+    node_tree.set_child_weight(root_id, c, -5).unwrap();
+    let d = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let root = node_tree.get_node(root_id).unwrap().expect_children();
+    assert_eq!(
+      root.weighted_children(),
+      vec![(c, -5), (b, 1), (a, 1), (d, 2)]
+    );
+
+    let root = node_tree
+      .get_node_mut(root_id)
+      .unwrap()
+      .expect_children_mut();
+    let e = Uuid::new_v4();
+    root.push_child(e);
+    assert_eq!(root.weight_of(e), Some(3));
+    let f = Uuid::new_v4();
+    root.insert_child_weighted(f, 2);
+    assert_eq!(root.weight_of(e), Some(3));
+    assert_eq!(root.children_slice(), [c, b, a, d, f, e]);
+
+    assert!(matches!(
+      node_tree.set_child_weight(root_id, root_id, 0),
+      Err(Error::NotAChild)
+    ));
+  }
+
+  #[test]
+  fn kind_name_labels_variants() {
+    let id = Uuid::new_v4();
+    assert_eq!(ChildrenNode::new(id, id).kind_name(), "children");
+    assert_eq!(LeftRightNode::new(id, id).kind_name(), "left_right");
+  }
+
+  #[test]
+  fn prune_subtrees_skips_pruned_branches() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    node_tree.add_child(a, NodeKind::Children).unwrap();
+    node_tree.add_child(a, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let b_1 = node_tree.add_child(b, NodeKind::LeftRight).unwrap();
+
+    let calls = Cell::new(0);
+    let pruned = node_tree.prune_subtrees(root_id, |_, node| {
+      calls.set(calls.get() + 1);
+      node.kind() == NodeKind::LeftRight
+    });
+    assert_eq!(pruned, vec![a, b_1]);
+    assert_eq!(calls.get(), 4);
+    assert_eq!(node_tree.count_where(root_id, |_| true), 2);
+    let root = node_tree.get_node(root_id).unwrap();
+    assert_eq!(root.children().collect::<Vec<_>>(), vec![b]);
+    assert_eq!(node_tree.get_node(b).unwrap().child_count(), 0);
+
+    assert_eq!(
+      node_tree.prune_subtrees(root_id, |_, _| true),
+      vec![root_id]
+    );
+    assert!(node_tree.iter().next().is_none());
+  }
+
+  #[test]
+  fn move_children_keeps_source_alive() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let from = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let a = node_tree.add_child(from, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(from, NodeKind::Children).unwrap();
+    let c = node_tree.add_child(from, NodeKind::Children).unwrap();
+    let to = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let existing = node_tree.add_child(to, NodeKind::Children).unwrap();
+    let binary = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+
+    assert!(matches!(
+      node_tree.move_children(from, binary),
+      Err(Error::NoFreeSlot)
+    ));
+    assert!(matches!(
+      node_tree.move_children(root_id, a),
+      Err(Error::WouldCreateCycle)
+    ));
+    node_tree.move_children(from, to).unwrap();
+    assert_eq!(node_tree.get_node(from).unwrap().child_count(), 0);
+    let target = node_tree.get_node(to).unwrap();
+    assert_eq!(
+      target.children().collect::<Vec<_>>(),
+      vec![existing, a, b, c]
+    );
+    for id in &[a, b, c] {
+      assert_eq!(node_tree.get_node(*id).unwrap().parent(), to);
+    }
+    assert!(matches!(
+      node_tree.move_children(Uuid::new_v4(), to),
+      Err(Error::NotFound(_))
+    ));
+  }
+
+  #[test]
+  fn explain_difference_reports_paths() {
+    let mut left = sequential_tree();
+    let left_root = left.make_root();
+    let a = left.add_child(left_root, NodeKind::Children).unwrap();
+    let b = left.add_child(left_root, NodeKind::Children).unwrap();
+    left.add_child(a, NodeKind::Children).unwrap();
+    left.add_child(b, NodeKind::LeftRight).unwrap();
+    left.add_child(b, NodeKind::Children).unwrap();
+
+    let mut right = sequential_tree();
+    let right_root = right.make_root();
+    let a = right.add_child(right_root, NodeKind::Children).unwrap();
+    let b = right.add_child(right_root, NodeKind::Children).unwrap();
+    right.add_child(right_root, NodeKind::Children).unwrap();
+    right.add_child(a, NodeKind::Children).unwrap();
+    right.add_child(b, NodeKind::Children).unwrap();
+
+    assert_eq!(left.explain_difference(left_root, &left, left_root), None);
+    assert_eq!(
+      left.explain_difference(left_root, &right, right_root).unwrap(),
+      "at root: left has 2 children, right has 3 children (extra right child at index 2)
+at path 1: left has 2 children, right has 1 child (extra left child at index 1)
+at path 1/0: left tree has LeftRightNode with 0 children, right tree has ChildrenNode with 0 children"
+    );
+
+    let mut wide = NodeTree::new();
+    let wide_root = wide.make_root();
+    let mut other = NodeTree::new();
+    let other_root = other.make_root();
+    for _ in 0..7 {
+      let id = wide.add_child(wide_root, NodeKind::Children).unwrap();
+      wide.add_child(id, NodeKind::Children).unwrap();
+      other.add_child(other_root, NodeKind::Children).unwrap();
+    }
+    let report = wide
+      .explain_difference(wide_root, &other, other_root)
+      .unwrap();
+    assert_eq!(report.lines().count(), EXPLAINED_DIFFERENCES_LIMIT + 1);
+    assert!(report.starts_with("at path 0: left has 1 child, right has 0 children"));
+    assert!(report.ends_with("… and 2 more differences"));
+  }
+
+  #[test]
+  fn deepest_node_and_widest_level() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    node_tree.add_child(a, NodeKind::Children).unwrap();
+    node_tree.add_child(b, NodeKind::Children).unwrap();
+    let b_right = node_tree.add_child(b, NodeKind::Children).unwrap();
+    let deepest = node_tree.add_child(b_right, NodeKind::Children).unwrap();
+
+    assert_eq!(node_tree.deepest_node(root_id), Some((deepest, 3)));
+    assert_eq!(node_tree.widest_level(root_id), Some((2, 3)));
+    assert_eq!(node_tree.deepest_node(deepest), Some((deepest, 0)));
+    assert_eq!(node_tree.widest_level(deepest), Some((0, 1)));
+    assert_eq!(node_tree.deepest_node(Uuid::new_v4()), None);
+    assert_eq!(node_tree.widest_level(Uuid::new_v4()), None);
+  }
+
+  #[test]
+  fn orphans_reports_both_flavors() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let wired = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    node_tree.add_child(wired, NodeKind::Children).unwrap();
+    let missing_parent = node_tree
+      .make_node(
+        root_id,
+        Box::new(|id, _| ChildrenNode::new(id, Uuid::new_v4())),
+      )
+      .unwrap();
+    let unreferenced = node_tree
+      .make_node(
+        wired,
+        Box::new(|id, parent| ChildrenNode::new(id, parent.id())),
+      )
+      .unwrap();
+
+    let mut orphans: Vec<Uuid> = node_tree.orphans().map(|(id, _)| id).collect();
+    orphans.sort();
+    let mut expected = vec![missing_parent, unreferenced];
+    expected.sort();
+    assert_eq!(orphans, expected);
+  }
+
+  #[test]
+  fn can_key_tree_by_caller_ids() {
+    let mut tree: GenericTree<&str> = GenericTree::new();
+    let root = tree.make_root_with_id("root").unwrap();
+    tree
+      .add_child_with_id(root, "left", NodeKind::LeftRight)
+      .unwrap();
+    tree
+      .make_node_with_id(
+        root,
+        "right",
+        Box::new(|id, parent| {
+          parent.add_child_id(id).unwrap();
+          ChildrenNode::new(id, parent.id())
+        }),
+      )
+      .unwrap();
+
+    assert_eq!(
+      tree.get_node("root").unwrap().child_ids(),
+      vec!["left", "right"]
+    );
+    assert_eq!(tree.get_node("right").unwrap().parent(), "root");
+    assert_eq!(tree.kind_of("left"), Some(NodeKind::LeftRight));
+    assert!(matches!(
+      tree.make_root_with_id("left"),
+      Err(Error::DuplicateId("left"))
+    ));
+    assert!(matches!(
+      tree.add_child_with_id("missing", "leaf", NodeKind::Children),
+      Err(Error::NotFound("missing"))
+    ));
+  }
+
+  #[test]
+  fn remove_many_handles_overlapping_subtrees() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let a1 = node_tree.add_child(a, NodeKind::Children).unwrap();
+    let a2 = node_tree.add_child(a1, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let c = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    let removed = node_tree.remove_many(&[a1, a, b, a2, Uuid::new_v4(), b]);
+
+    assert_eq!(
+      removed.iter().copied().collect::<HashSet<_>>(),
+      [a, a1, a2, b].iter().copied().collect::<HashSet<_>>()
+    );
+    assert_eq!(removed.len(), 4);
+    assert_eq!(node_tree.get_node(root_id).unwrap().child_ids(), vec![c]);
+    assert!(node_tree.get_node(a).is_none());
+    assert_eq!(node_tree.iter().count(), 2);
+  }
+
+  #[test]
+  fn display_summarizes_node() {
+    let mut node_tree = sequential_tree();
+    let root_id = node_tree.make_root();
+    let binary = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    node_tree.add_child(binary, NodeKind::Children).unwrap();
+
+    assert_eq!(
+      node_tree.get_node(root_id).unwrap().to_string(),
+      "ChildrenNode(00000001, 1 child)"
+    );
+    assert_eq!(
+      node_tree.get_node(binary).unwrap().to_string(),
+      "LeftRightNode(00000002, L=00000003 R=none)"
+    );
+  }
+
+  #[test]
+  fn root_reuses_existing_root() {
+    let mut node_tree = sequential_tree();
+    let root_id = node_tree.root();
+    node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    assert_eq!(node_tree.root(), root_id);
+    assert_eq!(node_tree.iter().count(), 2);
+
+    node_tree.make_root();
+    assert_eq!(node_tree.root(), root_id);
+  }
+
+  #[test]
+  fn subtree_snapshot_lists_nodes_in_preorder() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let a1 = node_tree.add_child(a, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    assert_eq!(
+      node_tree.subtree_snapshot(root_id),
+      Some(vec![
+        (root_id, root_id, "children"),
+        (a, root_id, "left_right"),
+        (a1, a, "children"),
+        (b, root_id, "children"),
+      ])
+    );
+    assert_eq!(
+      node_tree.subtree_snapshot(a1),
+      Some(vec![(a1, a, "children")])
+    );
+    assert_eq!(node_tree.subtree_snapshot(Uuid::new_v4()), None);
+  }
+
+  fn in_order(node_tree: &NodeTree, id: Option<Uuid>, ids: &mut Vec<Uuid>) {
+    if let Some(id) = id {
+      let inner_node = node_tree.get_node(id).unwrap().expect_left_right();
+      in_order(node_tree, inner_node.left, ids);
+      ids.push(id);
+      in_order(node_tree, inner_node.right, ids);
+    }
+  }
+
+  #[test]
+  fn rebalance_flattens_degenerate_chain() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let chain_root = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let mut chain = vec![chain_root];
+    for _ in 0..6 {
+      let parent = LeftRightId::try_from_uuid(&node_tree, *chain.last().unwrap()).unwrap();
+      chain.push(*node_tree.set_right(parent).unwrap());
+    }
+    let sibling = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    let new_root = node_tree.rebalance(chain_root).unwrap();
+
+    assert_eq!(new_root, chain[3]);
+    assert_eq!(node_tree.levels(new_root).unwrap().len(), 3);
+    let mut ids = Vec::new();
+    in_order(&node_tree, Some(new_root), &mut ids);
+    assert_eq!(ids, chain);
+    assert_eq!(node_tree.get_node(new_root).unwrap().parent(), root_id);
+    assert_eq!(
+      node_tree.get_node(root_id).unwrap().child_ids(),
+      vec![new_root, sibling]
+    );
+    assert_eq!(node_tree.orphans().count(), 0);
+
+    assert!(matches!(
+      node_tree.rebalance(root_id),
+      Err(Error::WrongNodeType { .. })
+    ));
+  }
+
+  #[test]
+  fn with_node_runs_closure_on_existing_nodes() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let child_id = Uuid::new_v4();
+
+    assert_eq!(
+      node_tree.with_node_mut(root_id, |node| node.add_child_id(child_id).is_ok()),
+      Some(true)
+    );
+    assert_eq!(node_tree.with_node(root_id, Node::child_count), Some(1));
+    assert_eq!(node_tree.with_node(child_id, Node::child_count), None);
+    assert_eq!(node_tree.with_node_mut(child_id, |_| ()), None);
+  }
+
+  #[test]
+  fn tree_height_counts_levels() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    assert_eq!(node_tree.tree_height(root_id), Some(1));
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let a1 = node_tree.add_child(a, NodeKind::LeftRight).unwrap();
+    assert_eq!(node_tree.tree_height(root_id), Some(3));
+    assert_eq!(node_tree.tree_height(a1), Some(1));
+    assert_eq!(node_tree.tree_height(Uuid::new_v4()), None);
+  }
+
+  #[test]
+  fn drain_where_returns_removed_subtrees() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let a1 = node_tree.add_child(a, NodeKind::Children).unwrap();
+    let a2 = node_tree.add_child(a, NodeKind::LeftRight).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    let drained = node_tree.drain_where(|node| node.kind() == NodeKind::LeftRight);
+
+    assert_eq!(
+      drained.iter().map(|(id, _)| *id).collect::<HashSet<_>>(),
+      [a, a1, a2].iter().copied().collect::<HashSet<_>>()
+    );
+    assert_eq!(drained.len(), 3);
+    let (_, node) = drained.iter().find(|(id, _)| *id == a).unwrap();
+    assert_eq!(node.child_ids(), vec![a1, a2]);
+    assert_eq!(node_tree.get_node(root_id).unwrap().child_ids(), vec![b]);
+    assert_eq!(node_tree.iter().count(), 2);
+  }
+
+  #[test]
+  fn attachments_are_typed_and_follow_nodes() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let child_id = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    node_tree.attach(child_id, (1.0f32, 2.0f32)).unwrap();
+    node_tree.attach(child_id, String::from("label")).unwrap();
+    node_tree.attach(root_id, 7u32).unwrap();
+    *node_tree.attachment_mut::<u32>(root_id).unwrap() += 1;
+
+    assert_eq!(
+      node_tree.attachment::<(f32, f32)>(child_id),
+      Some(&(1.0, 2.0))
+    );
+    assert_eq!(node_tree.attachment::<u32>(child_id), None);
+    assert!(matches!(
+      node_tree.attach(Uuid::new_v4(), 0u8),
+      Err(Error::NotFound(_))
+    ));
+
+    let mapping = node_tree.compact();
+    let (root_id, child_id) = (mapping[&root_id], mapping[&child_id]);
+    assert_eq!(node_tree.detach::<u32>(root_id), Some(8));
+    assert_eq!(node_tree.attachment::<u32>(root_id), None);
+    assert_eq!(
+      node_tree.attachment::<String>(child_id).map(String::as_str),
+      Some("label")
+    );
+
+    node_tree.remove_many(&[child_id]);
+    node_tree
+      .make_node_with_id(
+        root_id,
+        child_id,
+        Box::new(|id, parent| {
+          parent.add_child_id(id).unwrap();
+          ChildrenNode::new(id, parent.id())
+        }),
+      )
+      .unwrap();
+    assert_eq!(node_tree.attachment::<String>(child_id), None);
+  }
+
+  #[test]
+  fn ancestors_walk_up_to_root() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let a1 = node_tree.add_child(a, NodeKind::Children).unwrap();
+
+    assert_eq!(
+      node_tree.ancestors_inclusive(a1).collect::<Vec<_>>(),
+      vec![a1, a, root_id]
+    );
+    assert_eq!(
+      node_tree.ancestors(a1).collect::<Vec<_>>(),
+      vec![a, root_id]
+    );
+    assert_eq!(
+      node_tree.ancestors_inclusive(root_id).collect::<Vec<_>>(),
+      vec![root_id]
+    );
+    assert_eq!(node_tree.ancestors(root_id).count(), 0);
+    assert_eq!(node_tree.ancestors_inclusive(Uuid::new_v4()).count(), 0);
+  }
+
+  #[test]
+  fn same_shape_ignores_ids() {
+    let mut left = NodeTree::new();
+    let left_root = left.make_root();
+    let binary = left.add_child(left_root, NodeKind::LeftRight).unwrap();
+    left.add_child(binary, NodeKind::Children).unwrap();
+    let mut right = sequential_tree();
+    let right_root = right.make_root();
+    let binary = right.add_child(right_root, NodeKind::LeftRight).unwrap();
+    let leaf = right.add_child(binary, NodeKind::Children).unwrap();
+
+    assert!(left.same_shape(left_root, &right, right_root));
+    right.add_child(leaf, NodeKind::Children).unwrap();
+    assert!(!left.same_shape(left_root, &right, right_root));
+    assert!(!left.same_shape(Uuid::new_v4(), &right, Uuid::new_v4()));
+  }
+
+  #[test]
+  fn insert_between_splices_edge() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    let wrapper = node_tree
+      .insert_between(root_id, a, Box::new(|id| LeftRightNode::new(id, id)))
+      .unwrap();
+
+    assert_eq!(
+      node_tree.get_node(root_id).unwrap().child_ids(),
+      vec![wrapper, b]
+    );
+    let wrapper_node = node_tree.get_node(wrapper).unwrap();
+    assert_eq!(wrapper_node.parent(), root_id);
+    assert_eq!(wrapper_node.child_ids(), vec![a]);
+    assert_eq!(node_tree.get_node(a).unwrap().parent(), wrapper);
+
+    assert!(matches!(
+      node_tree.insert_between(root_id, a, Box::new(|id| ChildrenNode::new(id, id))),
+      Err(Error::NotAChild)
+    ));
+    assert_eq!(node_tree.iter().count(), 4);
+  }
+
+  #[test]
+  fn preorder_and_postorder_ids() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let left = node_tree.add_child(a, NodeKind::Children).unwrap();
+    let right = node_tree.add_child(a, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    assert_eq!(
+      node_tree.preorder_ids(root_id),
+      vec![root_id, a, left, right, b]
+    );
+    assert_eq!(
+      node_tree.postorder_ids(root_id),
+      vec![left, right, a, b, root_id]
+    );
+    assert_eq!(node_tree.postorder_ids(b), vec![b]);
+    assert!(node_tree.preorder_ids(Uuid::new_v4()).is_empty());
+    assert!(node_tree.postorder_ids(Uuid::new_v4()).is_empty());
+  }
+
+  #[test]
+  fn as_map_exposes_nodes() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let child_id = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let map = node_tree.as_map();
+    assert_eq!(map.len(), 2);
+    assert_eq!(map[&child_id].parent(), root_id);
+  }
+
+  #[test]
+  fn inner_of_distinguishes_failures() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let binary = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+
+    assert_eq!(
+      node_tree.inner_of::<ChildrenNode>(root_id).unwrap().id,
+      root_id
+    );
+    assert_eq!(
+      node_tree.inner_of::<LeftRightNode>(binary).unwrap().parent,
+      root_id
+    );
+    assert!(matches!(
+      node_tree.inner_of::<ChildrenNode>(binary),
+      Err(Error::WrongNodeType {
+        expected: "children",
+        found: "left_right",
+        ..
+      })
+    ));
+    let missing = Uuid::new_v4();
+    assert!(matches!(
+      node_tree.inner_of::<LeftRightNode>(missing),
+      Err(Error::NotFound(id)) if id == missing
+    ));
+  }
+
+  #[test]
+  fn children_of_loads_children_once() {
+    let calls = std::sync::Arc::new(AtomicU64::new(0));
+    let mut tree: GenericTree<String> = GenericTree::new();
+    let root = tree.make_root_with_id("root".to_string()).unwrap();
+    let loader_calls = calls.clone();
+    tree.set_loader(Box::new(move |id: String| {
+      loader_calls.fetch_add(1, Ordering::Relaxed);
+      if id.contains('/') {
+        return Vec::new();
+      }
+      vec![
+        ChildrenNode::new(format!("{}/a", id), id.clone()),
+        LeftRightNode::new(format!("{}/b", id), id),
+      ]
+    }));
+
+    assert!(!tree.is_expanded(root.clone()));
+    assert_eq!(
+      tree.children_of(root.clone()).unwrap(),
+      vec!["root/a".to_string(), "root/b".to_string()]
+    );
+    assert!(tree.is_expanded(root.clone()));
+    assert_eq!(tree.children_of(root.clone()).unwrap().len(), 2);
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+    assert_eq!(
+      tree.get_node("root/b".to_string()).unwrap().parent(),
+      "root"
+    );
+    assert!(tree.children_of("root/a".to_string()).unwrap().is_empty());
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+    assert!(matches!(
+      tree.children_of("missing".to_string()),
+      Err(Error::NotFound(_))
+    ));
+  }
+
+  #[test]
+  fn to_newick_nests_children() {
+    let mut node_tree = sequential_tree();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    node_tree.add_child(a, NodeKind::Children).unwrap();
+    node_tree.add_child(a, NodeKind::Children).unwrap();
+    node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    assert_eq!(
+      node_tree.to_newick(root_id).unwrap(),
+      "((00000003,00000004)00000002,00000005)00000001;"
+    );
+    assert_eq!(
+      node_tree.to_newick(a).unwrap(),
+      "(00000003,00000004)00000002;"
+    );
+    assert_eq!(node_tree.to_newick(Uuid::new_v4()), None);
+  }
+
+  #[test]
+  fn from_newick_round_trips_labels() {
+    let (node_tree, root_id) = NodeTree::from_newick("((A, B)C, (D)) E;\n").unwrap();
+
+    assert_eq!(node_tree.iter().count(), 6);
+    assert_eq!(node_tree.get_node(root_id).unwrap().child_count(), 2);
+    assert_eq!(
+      node_tree.attachment::<NewickLabel>(root_id),
+      Some(&NewickLabel("E".to_string()))
+    );
+    let newick = node_tree.to_newick(root_id).unwrap();
+    let unlabelled = node_tree.get_node(root_id).unwrap().child_ids()[1];
+    assert_eq!(
+      newick,
+      format!("((A,B)C,(D){})E;", &unlabelled.to_simple().to_string()[..8])
+    );
+    let (other, other_root) = NodeTree::from_newick(&newick).unwrap();
+    assert!(node_tree.same_shape(root_id, &other, other_root));
+
+    let (leaf_tree, leaf) = NodeTree::from_newick(";").unwrap();
+    assert_eq!(leaf_tree.get_node(leaf).unwrap().child_count(), 0);
+  }
+
+  #[test]
+  fn to_newick_quotes_special_labels() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let labels = ["a,b", "it's", "two words", "", "(x):1;"];
+    for label in labels.iter() {
+      let id = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+      node_tree
+        .attach(id, NewickLabel(label.to_string()))
+        .unwrap();
+    }
+    node_tree
+      .attach(root_id, NewickLabel("r".to_string()))
+      .unwrap();
+
+    let newick = node_tree.to_newick(root_id).unwrap();
+    assert_eq!(newick, "('a,b','it''s','two words','','(x):1;')r;");
+    let (other, other_root) = NodeTree::from_newick(&newick).unwrap();
+    assert_eq!(other.iter().count(), 6);
+    let read_back: Vec<String> = other
+      .get_node(other_root)
+      .unwrap()
+      .children()
+      .map(|id| other.attachment::<NewickLabel>(id).unwrap().0.clone())
+      .collect();
+    assert_eq!(read_back, labels);
+  }
+
+  #[test]
+  fn from_newick_rejects_malformed_input() {
+    for (input, expected_position) in &[
+      ("(A,B", 4),
+      ("(A,B));", 5),
+      ("(A,B;", 4),
+      ("A,B;", 1),
+      ("(A B);", 3),
+      ("(A:0.1);", 2),
+      ("(A)B; C", 6),
+      ("A(B);", 1),
+      ("('A);", 1),
+      ("(A'B');", 2),
+    ] {
+      match NodeTree::from_newick(input) {
+        Err(Error::InvalidNewick { position, .. }) => {
+          assert_eq!(position, *expected_position, "{}", input)
+        }
+        other => panic!("{}: {:?}", input, other.map(|(_, root)| root)),
+      }
+    }
+  }
+
+  #[test]
+  fn rotate_children_wraps_around() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let ids: Vec<Uuid> = (0..4)
+      .map(|_| node_tree.add_child(root_id, NodeKind::Children).unwrap())
+      .collect();
+    let children = |node_tree: &NodeTree| node_tree.get_node(root_id).unwrap().child_ids();
+
+    node_tree.rotate_children(root_id, 1).unwrap();
+    assert_eq!(children(&node_tree), vec![ids[1], ids[2], ids[3], ids[0]]);
+    node_tree.rotate_children(root_id, -6).unwrap();
+    assert_eq!(children(&node_tree), vec![ids[3], ids[0], ids[1], ids[2]]);
+    assert_eq!(
+      node_tree
+        .get_node(root_id)
+        .unwrap()
+        .expect_children()
+        .weight_of(ids[0]),
+      Some(1)
+    );
+
+    node_tree.rotate_children(ids[0], 3).unwrap();
+    let binary = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    assert!(matches!(
+      node_tree.rotate_children(binary, 1),
+      Err(Error::WrongNodeType { .. })
+    ));
+  }
+
+  #[test]
+  fn rotate_children_keeps_explicit_weights() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let ids: Vec<Uuid> = (0..3)
+      .map(|_| node_tree.add_child(root_id, NodeKind::Children).unwrap())
+      .collect();
+    node_tree.set_child_weight(root_id, ids[2], 100).unwrap();
+    node_tree.set_child_weight(root_id, ids[1], 2).unwrap();
+    node_tree.set_child_weight(root_id, ids[0], 1).unwrap();
+    let weighted = |node_tree: &NodeTree| {
+      node_tree
+        .get_node(root_id)
+        .unwrap()
+        .expect_children()
+        .weighted_children()
+    };
+    let before = weighted(&node_tree);
+    assert_eq!(before, vec![(ids[0], 1), (ids[1], 2), (ids[2], 100)]);
+
+    assert!(matches!(
+      node_tree.rotate_children(root_id, 1),
+      Err(Error::WeightedChildren(id)) if id == root_id
+    ));
+    assert_eq!(weighted(&node_tree), before);
+  }
+
+  #[test]
+  fn has_cycle_follows_parent_links() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(a, NodeKind::Children).unwrap();
+    node_tree.add_child(b, NodeKind::LeftRight).unwrap();
+    assert!(!node_tree.has_cycle());
+
+    node_tree
+      .get_node_mut(a)
+      .unwrap()
+      .set_parent(Uuid::new_v4());
+    assert!(!node_tree.has_cycle());
+    node_tree.get_node_mut(a).unwrap().set_parent(b);
+    assert!(node_tree.has_cycle());
+  }
+
+  #[test]
+  fn prune_unreachable_removes_orphans() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let a1 = node_tree.add_child(a, NodeKind::LeftRight).unwrap();
+    let a2 = node_tree.add_child(a1, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    node_tree.get_node_mut(a).unwrap().remove_child_id(a1);
+
+    let removed = node_tree.prune_unreachable();
+
+    assert_eq!(
+      removed.into_iter().collect::<HashSet<_>>(),
+      [a1, a2].iter().copied().collect::<HashSet<_>>()
+    );
+    assert_eq!(node_tree.iter().count(), 3);
+    assert!(node_tree.get_node(b).is_some());
+    assert!(node_tree.prune_unreachable().is_empty());
+  }
+
+  #[test]
+  fn for_each_edge_reports_edge_kinds() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let binary = node_tree.add_left_right_child(root_id).unwrap();
+    let right = node_tree.set_right(binary).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    let mut edges = Vec::new();
+    node_tree.for_each_edge(root_id, |parent, child, kind| {
+      edges.push((parent, child, kind))
+    });
+
+    assert_eq!(
+      edges,
+      vec![
+        (root_id, *binary, EdgeKind::Child(0)),
+        (root_id, b, EdgeKind::Child(1)),
+        (*binary, *right, EdgeKind::Right),
+      ]
+    );
+  }
+
+  #[test]
+  fn absorb_keeps_trees_separate_and_remaps_collisions() {
+    let mut node_tree = sequential_tree();
+    let root_id = node_tree.make_root();
+    node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let mut other = sequential_tree();
+    let other_root = other.make_root();
+    let other_child = other.add_child(other_root, NodeKind::LeftRight).unwrap();
+    let other_leaf = other.add_child(other_child, NodeKind::Children).unwrap();
+    other.attach(other_leaf, 5u8).unwrap();
+    let second_root = other.make_root();
+
+    let roots = node_tree.absorb(other);
+
+    assert_eq!(roots.len(), 2);
+    assert_eq!(node_tree.iter().count(), 6);
+    assert!(!roots.contains(&root_id));
+    assert!(roots.contains(&second_root));
+    let absorbed_root = *roots.iter().find(|id| **id != second_root).unwrap();
+    assert_eq!(node_tree.tree_height(absorbed_root), Some(3));
+    let leaf = node_tree.preorder_ids(absorbed_root)[2];
+    assert_eq!(node_tree.attachment::<u8>(leaf), Some(&5));
+    assert_eq!(node_tree.orphans().count(), 0);
+    assert_eq!(node_tree.get_node(root_id).unwrap().child_count(), 1);
+  }
+
+  #[test]
+  fn tree_child_count_covers_both_kinds() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let binary = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    node_tree.add_child(binary, NodeKind::Children).unwrap();
+
+    assert_eq!(node_tree.child_count(root_id), 2);
+    assert_eq!(node_tree.child_count(binary), 1);
+    assert_eq!(node_tree.child_count(Uuid::new_v4()), 0);
+  }
+
+  #[test]
+  fn set_root_reverses_the_path() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let sibling = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(a, NodeKind::Children).unwrap();
+    let c = node_tree.add_child(a, NodeKind::Children).unwrap();
+    let d = node_tree.add_child(b, NodeKind::Children).unwrap();
+
+    node_tree.set_root(b).unwrap();
+
+    let children = |id| node_tree.get_node(id).unwrap().child_ids();
+    assert_eq!(node_tree.get_node(b).unwrap().parent(), b);
+    assert_eq!(children(b), vec![d, a]);
+    assert_eq!(node_tree.get_node(a).unwrap().parent(), b);
+    assert_eq!(children(a), vec![root_id, c]);
+    assert_eq!(node_tree.get_node(root_id).unwrap().parent(), a);
+    assert_eq!(children(root_id), vec![sibling]);
+    assert_eq!(node_tree.orphans().count(), 0);
+    assert!(!node_tree.has_cycle());
+    assert_eq!(node_tree.preorder_ids(b).len(), 6);
+
+    node_tree.set_root(b).unwrap();
+    assert_eq!(node_tree.get_node(b).unwrap().child_ids(), vec![d, a]);
+    assert!(matches!(node_tree.set_root(a), Err(Error::NoFreeSlot)));
+    assert!(matches!(
+      node_tree.set_root(Uuid::new_v4()),
+      Err(Error::NotFound(_))
+    ));
+  }
+
+  #[test]
+  fn binary_shape_checks() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let top = node_tree.add_left_right_child(root_id).unwrap();
+    let left = node_tree.set_left(top).unwrap();
+    assert!(!node_tree.is_full_binary(*top));
+    assert!(node_tree.is_complete_binary(*top));
+
+    let right = node_tree.set_right(top).unwrap();
+    assert!(node_tree.is_full_binary(*top));
+    assert!(node_tree.is_complete_binary(*top));
+
+    node_tree.set_right(right).unwrap();
+    assert!(!node_tree.is_complete_binary(*top));
+    node_tree.set_left(right).unwrap();
+    assert!(node_tree.is_full_binary(*top));
+    assert!(!node_tree.is_complete_binary(*top));
+    node_tree.set_left(left).unwrap();
+    node_tree.set_right(left).unwrap();
+    assert!(node_tree.is_complete_binary(*top));
+
+    assert!(!node_tree.is_full_binary(root_id));
+    assert!(!node_tree.is_complete_binary(root_id));
+    assert!(!node_tree.is_full_binary(Uuid::new_v4()));
+    assert!(!node_tree.is_complete_binary(Uuid::new_v4()));
+  }
+
+  #[test]
+  fn entry_inserts_missing_nodes_once() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let id = Uuid::new_v4();
+    let factory =
+      || -> NodeFactory { Box::new(|id, parent| LeftRightNode::new(id, attach_child(parent, id))) };
+
+    assert!(matches!(node_tree.entry(id), Entry::Vacant(_)));
+    let node = node_tree
+      .entry(id)
+      .or_insert_with(root_id, factory())
+      .unwrap();
+    assert_eq!(node.kind(), NodeKind::LeftRight);
+    node.add_child_id(Uuid::new_v4()).unwrap();
+    let node = node_tree
+      .entry(id)
+      .or_insert_with(root_id, factory())
+      .unwrap();
+    assert_eq!(node.child_count(), 1);
+    assert_eq!(node_tree.child_count(root_id), 1);
+
+    match node_tree.entry(root_id) {
+      Entry::Occupied(entry) => assert_eq!(entry.get().child_ids(), vec![id]),
+      Entry::Vacant(_) => panic!("root should be occupied"),
+    }
+    assert!(matches!(
+      node_tree
+        .entry(Uuid::new_v4())
+        .or_insert_with(Uuid::new_v4(), factory()),
+      Err(Error::NotFound(_))
+    ));
+  }
+
+  #[test]
+  fn descendants_by_depth_orders_levels() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let a1 = node_tree.add_child(a, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let b1 = node_tree.add_child(b, NodeKind::Children).unwrap();
+    let a1x = node_tree.add_child(a1, NodeKind::Children).unwrap();
+
+    assert_eq!(
+      node_tree.descendants_by_depth(root_id),
+      vec![(1, a), (1, b), (2, a1), (2, b1), (3, a1x)]
+    );
+    assert!(node_tree.descendants_by_depth(a1x).is_empty());
+    assert!(node_tree.descendants_by_depth(Uuid::new_v4()).is_empty());
+  }
+
+  #[test]
+  fn dfs_filtered_descends_through_other_kinds() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let a1 = node_tree.add_child(a, NodeKind::Children).unwrap();
+    let a2 = node_tree.add_child(a, NodeKind::LeftRight).unwrap();
+    let a2x = node_tree.add_child(a2, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    assert_eq!(
+      node_tree
+        .dfs_filtered(root_id, NodeKind::Children)
+        .collect::<Vec<_>>(),
+      vec![root_id, a1, a2x, b]
+    );
+    assert_eq!(
+      node_tree
+        .dfs_filtered(root_id, NodeKind::LeftRight)
+        .collect::<Vec<_>>(),
+      vec![a, a2]
+    );
+    assert_eq!(
+      node_tree
+        .dfs_filtered(Uuid::new_v4(), NodeKind::Children)
+        .count(),
+      0
+    );
+  }
+
+  #[test]
+  fn reserve_ids_can_be_placed_later() {
+    let mut node_tree = sequential_tree();
+    let root_id = node_tree.make_root();
+    let ids = node_tree.reserve_ids(3);
+    assert_eq!(ids.len(), 3);
+    assert!(!ids.contains(&root_id));
+    assert_eq!(node_tree.iter().count(), 1);
+
+    node_tree
+      .add_child_with_id(root_id, ids[2], NodeKind::Children)
+      .unwrap();
+    node_tree
+      .add_child_with_id(ids[2], ids[0], NodeKind::LeftRight)
+      .unwrap();
+    let minted = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    assert!(!ids.contains(&minted));
+    assert_eq!(
+      node_tree.preorder_ids(root_id),
+      vec![root_id, ids[2], ids[0], minted]
+    );
+  }
+
+  #[test]
+  fn into_nested_mirrors_structure() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let binary = node_tree.add_left_right_child(root_id).unwrap();
+    node_tree.set_right(binary).unwrap();
+    node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    assert_eq!(
+      node_tree.into_nested(root_id),
+      Some(NestedNode::Children(vec![
+        NestedNode::LeftRight(None, Some(Box::new(NestedNode::LeftRight(None, None)))),
+        NestedNode::Children(Vec::new()),
+      ]))
+    );
+    assert_eq!(NodeTree::new().into_nested(Uuid::new_v4()), None);
+  }
+
+  #[test]
+  fn from_nested_round_trips() {
+    let nested = NestedNode::Children(vec![
+      NestedNode::LeftRight(None, Some(Box::new(NestedNode::Children(Vec::new())))),
+      NestedNode::Children(vec![NestedNode::LeftRight(None, None)]),
+    ]);
+
+    let (node_tree, root_id) = NodeTree::from_nested(nested.clone());
+
+    assert_eq!(node_tree.iter().count(), 5);
+    assert_eq!(node_tree.orphans().count(), 0);
+    let binary = node_tree.get_node(root_id).unwrap().child_ids()[0];
+    let binary = node_tree.get_node(binary).unwrap().expect_left_right();
+    assert!(binary.left.is_none() && binary.right.is_some());
+    assert_eq!(node_tree.into_nested(root_id), Some(nested));
+
+    let (leaf_tree, leaf) = NodeTree::from_nested(NestedNode::LeftRight(None, None));
+    assert_eq!(leaf_tree.get_node(leaf).unwrap().parent(), leaf);
+  }
+
+  #[test]
+  fn common_ancestor_path_uses_structural_indices() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let binary = node_tree.add_left_right_child(root_id).unwrap();
+    let right = node_tree.set_right(binary).unwrap();
+    let x = node_tree.set_left(right).unwrap();
+    let y = node_tree.set_right(right).unwrap();
+
+    assert_eq!(node_tree.common_ancestor_path(*x, *y), Some(vec![1, 1]));
+    assert_eq!(node_tree.common_ancestor_path(*x, *right), Some(vec![1, 1]));
+    assert_eq!(node_tree.common_ancestor_path(*x, *x), Some(vec![1, 1, 0]));
+    let first = node_tree.get_node(root_id).unwrap().child_ids()[0];
+    assert_eq!(node_tree.common_ancestor_path(first, *y), Some(vec![]));
+
+    let other_root = node_tree.make_root();
+    assert_eq!(node_tree.common_ancestor_path(other_root, *y), None);
+    assert_eq!(node_tree.common_ancestor_path(Uuid::new_v4(), *y), None);
+  }
+
+  #[test]
+  fn collapse_chains_keeps_roots() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(a, NodeKind::Children).unwrap();
+    let binary = node_tree.add_child(b, NodeKind::LeftRight).unwrap();
+    let c = node_tree.add_child(binary, NodeKind::Children).unwrap();
+    let leaf = node_tree.add_child(c, NodeKind::Children).unwrap();
+    let d = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+
+    assert_eq!(node_tree.collapse_chains(), 3);
+
+    assert_eq!(
+      node_tree.get_node(root_id).unwrap().child_ids(),
+      vec![binary, d]
+    );
+    assert_eq!(node_tree.get_node(binary).unwrap().parent(), root_id);
+    assert_eq!(node_tree.get_node(binary).unwrap().child_ids(), vec![leaf]);
+    assert_eq!(node_tree.get_node(leaf).unwrap().parent(), binary);
+    assert_eq!(node_tree.iter().count(), 4);
+    assert_eq!(node_tree.collapse_chains(), 0);
+  }
+
+  #[test]
+  fn gen_balanced_node_count() {
+    let (node_tree, root_id) = NodeTree::gen_balanced(3, 3);
+    assert_eq!(node_tree.iter().count(), (3usize.pow(4) - 1) / 2);
+    assert_eq!(node_tree.tree_height(root_id), Some(4));
+    assert!(node_tree
+      .iter()
+      .all(|(_, node)| node.child_count() == 0 || node.child_count() == 3));
+
+    let (chain, _) = NodeTree::gen_balanced(4, 1);
+    assert_eq!(chain.iter().count(), 5);
+    let (lone, root_id) = NodeTree::gen_balanced(0, 5);
+    assert_eq!(lone.iter().count(), 1);
+    assert_eq!(lone.get_node(root_id).unwrap().child_count(), 0);
+  }
+
+  #[test]
+  fn for_each_visits_every_node() {
+    let (node_tree, _) = NodeTree::gen_balanced(3, 2);
+    let mut seen = HashSet::new();
+    node_tree.for_each(|id, node| {
+      assert_eq!(node.id(), id);
+      seen.insert(id);
+    });
+    assert_eq!(seen.len(), 15);
+  }
+
+  #[cfg(feature = "rayon")]
+  #[test]
+  fn par_for_each_visits_every_node() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    let (node_tree, _) = NodeTree::gen_balanced(4, 3);
+    let visited = AtomicUsize::new(0);
+    let seen = Mutex::new(HashSet::new());
+    node_tree.par_for_each(|id, node| {
+      assert_eq!(node.id(), id);
+      visited.fetch_add(1, Ordering::Relaxed);
+      seen.lock().unwrap().insert(id);
+    });
+    assert_eq!(visited.into_inner(), 121);
+    assert_eq!(seen.into_inner().unwrap().len(), 121);
+  }
+
+  #[test]
+  fn path_string_joins_short_ids_from_root() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let child_id = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let leaf_id = node_tree.add_child(child_id, NodeKind::Children).unwrap();
+    let short = |id: Uuid| id.to_simple().to_string()[..8].to_string();
+
+    assert_eq!(node_tree.path_string(root_id), Some(short(root_id)));
+    assert_eq!(
+      node_tree.path_string(leaf_id),
+      Some(format!(
+        "{}/{}/{}",
+        short(root_id),
+        short(child_id),
+        short(leaf_id)
+      ))
+    );
+    assert_eq!(node_tree.path_string(Uuid::new_v4()), None);
+  }
+
+  #[test]
+  fn mirror_node_swaps_slots() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let binary = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let left = node_tree.add_child(binary, NodeKind::Children).unwrap();
+
+    node_tree.mirror_node(binary).unwrap();
+    let inner = node_tree.get_node(binary).unwrap().expect_left_right();
+    assert_eq!((inner.left, inner.right), (None, Some(left)));
+
+    match node_tree.mirror_node(root_id) {
+      Err(Error::WrongNodeType { id, found, .. }) => {
+        assert_eq!(id, root_id);
+        assert_eq!(found, "children");
+      }
+      other => panic!("unexpected result {:?}", other),
+    }
+    assert!(matches!(
+      node_tree.mirror_node(Uuid::nil()),
+      Err(Error::NotFound(_))
+    ));
+  }
+
+  #[test]
+  fn mirror_subtree_reflects_binary_nodes() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let top = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let a = node_tree.add_child(top, NodeKind::LeftRight).unwrap();
+    let b = node_tree.add_child(top, NodeKind::LeftRight).unwrap();
+    node_tree.add_child(a, NodeKind::LeftRight).unwrap();
+    node_tree.add_child(b, NodeKind::LeftRight).unwrap();
+    node_tree.add_child(b, NodeKind::LeftRight).unwrap();
+
+    let mut before = Vec::new();
+    in_order(&node_tree, Some(top), &mut before);
+    node_tree.mirror_subtree(top).unwrap();
+    let mut after = Vec::new();
+    in_order(&node_tree, Some(top), &mut after);
+    after.reverse();
+    assert_eq!(before, after);
+    assert!(matches!(
+      node_tree.mirror_subtree(Uuid::nil()),
+      Err(Error::NotFound(_))
+    ));
+  }
+
+  #[test]
+  fn mirror_subtree_walks_through_children_nodes() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let first = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    let second = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let leaf = node_tree.add_child(first, NodeKind::Children).unwrap();
+
+    node_tree.mirror_subtree(root_id).unwrap();
+    assert_eq!(
+      node_tree.get_node(root_id).unwrap().child_ids(),
+      vec![first, second]
+    );
+    let inner = node_tree.get_node(first).unwrap().expect_left_right();
+    assert_eq!((inner.left, inner.right), (None, Some(leaf)));
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  fn assert_invariants_accepts_consistent_trees() {
+    let (mut node_tree, root_id) = NodeTree::gen_balanced(3, 3);
+    node_tree.assert_invariants();
+    let binary = node_tree.add_child(root_id, NodeKind::LeftRight).unwrap();
+    node_tree.add_child(binary, NodeKind::Children).unwrap();
+    node_tree.make_root();
+    node_tree.assert_invariants();
+    node_tree.collapse_chains();
+    node_tree.mirror_subtree(root_id).unwrap();
+    node_tree.assert_invariants();
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  #[should_panic(expected = "has parent")]
+  fn assert_invariants_catches_wrong_parent() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let child_id = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let leaf_id = node_tree.add_child(child_id, NodeKind::Children).unwrap();
+    node_tree
+      .get_node_mut(leaf_id)
+      .unwrap()
+      .expect_children_mut()
+      .parent = root_id;
+    node_tree.assert_invariants();
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  #[should_panic(expected = "which does not list it")]
+  fn assert_invariants_catches_unlisted_child() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let child_id = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    node_tree
+      .get_node_mut(root_id)
+      .unwrap()
+      .expect_children_mut()
+      .remove_child(&child_id);
+    node_tree.assert_invariants();
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  #[should_panic(expected = "is listed more than once")]
+  fn assert_invariants_catches_duplicate_child() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let child_id = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    node_tree
+      .get_node_mut(root_id)
+      .unwrap()
+      .expect_children_mut()
+      .push_child(child_id);
+    node_tree.assert_invariants();
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  #[should_panic(expected = "parent links form a cycle")]
+  fn assert_invariants_catches_detached_cycle() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(a, NodeKind::Children).unwrap();
+    node_tree
+      .get_node_mut(root_id)
+      .unwrap()
+      .expect_children_mut()
+      .remove_child(&a);
+    let inner = node_tree.get_node_mut(a).unwrap().expect_children_mut();
+    inner.parent = b;
+    node_tree
+      .get_node_mut(b)
+      .unwrap()
+      .expect_children_mut()
+      .push_child(a);
+    node_tree.assert_invariants();
+  }
+
+  #[test]
+  fn typed_ids_tolerate_reuse_after_compact() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let container = node_tree.add_children_child(root_id).unwrap();
+    let mapping = node_tree.compact();
+    let root_id = mapping[&root_id];
+    let container = ChildrenId::try_from_uuid(&node_tree, mapping[&*container]).unwrap();
+
+    node_tree.remove_many(&[*container]);
+    node_tree.add_left_right_child(root_id).unwrap();
+    let mapping = node_tree.compact();
+    let root_id = mapping[&root_id];
+    assert_eq!(
+      node_tree.get_node(*container).unwrap().kind(),
+      NodeKind::LeftRight
+    );
+    assert!(node_tree.get_children_node(container).is_none());
+
+    let binary = LeftRightId::try_from_uuid(&node_tree, *container).unwrap();
+    node_tree.remove_many(&[*binary]);
+    node_tree.add_children_child(root_id).unwrap();
+    node_tree.compact();
+    assert!(node_tree.get_left_right_node(binary).is_none());
+    assert!(matches!(
+      node_tree.set_left(binary),
+      Err(Error::WrongNodeType {
+        expected: "left_right",
+        found: "children",
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn removing_children_keeps_weights() {
+    let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let mut node = ChildrenNode::new_inner(Uuid::nil(), Uuid::nil());
+    node.push_child(a);
+    node.push_child(b);
+    node.push_child(c);
+    assert!(node.remove_child(&a));
+    assert_eq!(node.weighted_children(), vec![(b, 1), (c, 2)]);
+
+    let mut plain = ChildrenNode::new_inner(Uuid::nil(), Uuid::nil());
+    plain.push_child(a);
+    plain.push_child(b);
+    let mut weighted = ChildrenNode::new_inner(Uuid::nil(), Uuid::nil());
+    weighted.push_child(a);
+    weighted.insert_child_weighted(c, -1);
+    weighted.push_child(b);
+    assert_ne!(weighted, plain);
+    assert!(weighted.remove_child(&c));
+    assert_eq!(weighted.weighted_children(), vec![(a, 0), (b, 1)]);
+    assert_eq!(weighted, plain);
+  }
+
+  #[test]
+  fn trees_are_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<NodeTree>();
+    assert_send_sync::<GenericTree<String>>();
+  }
+
+  #[test]
+  fn levels_skip_dangling_child_ids() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let dangling = Uuid::new_v4();
+    node_tree
+      .get_node_mut(a)
+      .unwrap()
+      .expect_children_mut()
+      .push_child(dangling);
+
+    assert_eq!(
+      node_tree.levels(root_id).unwrap(),
+      vec![vec![root_id], vec![a]]
+    );
+    assert!(node_tree.nodes_at_depth(root_id, 2).unwrap().is_empty());
+    assert_eq!(node_tree.tree_height(root_id), Some(2));
+    assert_eq!(node_tree.deepest_node(root_id), Some((a, 1)));
+  }
+
+  #[test]
+  fn fresh_ids_skip_ids_taken_by_compact() {
+    let counter = AtomicU64::new(0);
+    let mut node_tree = NodeTree::with_id_generator(Box::new(move || {
+      Uuid::from_u128(u128::from(counter.fetch_add(1, Ordering::Relaxed) + 1))
+    }));
+    let root_id = node_tree.make_root();
+    node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let (other, _) = NodeTree::gen_balanced(1, 2);
+    node_tree.absorb(other);
+    node_tree.compact();
+
+    let new_root = node_tree.make_root();
+    let child = node_tree.add_child(new_root, NodeKind::LeftRight).unwrap();
+    let left = LeftRightId::try_from_uuid(&node_tree, child).unwrap();
+    node_tree.set_left(left).unwrap();
+    assert_eq!(node_tree.iter().count(), 8);
+    assert_eq!(
+      node_tree.get_node(new_root).unwrap().child_ids(),
+      vec![child]
+    );
+    #[cfg(debug_assertions)]
+    node_tree.assert_invariants();
+  }
+
+  #[test]
+  fn depth_terminates_on_parent_cycles() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let a = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let b = node_tree.add_child(a, NodeKind::Children).unwrap();
+    node_tree
+      .get_node_mut(a)
+      .unwrap()
+      .expect_children_mut()
+      .parent = b;
+
+    let depth = node_tree.node_ref(b).unwrap().depth();
+    assert!(depth < node_tree.iter().count());
+  }
+
+  #[test]
+  fn promote_left_right_uses_first_free_slot() {
+    let mut node_tree = NodeTree::new();
+    let root_id = node_tree.make_root();
+    let parent_id = node_tree.add_child(root_id, NodeKind::Children).unwrap();
+    let binary = node_tree.add_left_right_child(parent_id).unwrap();
+    let right = node_tree.set_right(binary).unwrap();
+
+    node_tree.promote(*binary).unwrap();
+    let inner = node_tree.get_left_right_node(binary).unwrap();
+    assert_eq!((inner.left, inner.right), (Some(parent_id), Some(*right)));
+    assert_eq!(
+      node_tree.get_node(root_id).unwrap().child_ids(),
+      vec![*binary]
+    );
+    assert_eq!(node_tree.get_node(parent_id).unwrap().parent(), *binary);
+  }
+
+  #[test]
+  fn debug_and_display_work_for_other_keys() {
+    let mut tree: GenericTree<String> = GenericTree::new();
+    let root = tree.make_root_with_id("root".to_string()).unwrap();
+    tree
+      .add_child_with_id(root.clone(), "b".to_string(), NodeKind::LeftRight)
+      .unwrap();
+    tree
+      .add_child_with_id("b".to_string(), "a".to_string(), NodeKind::Children)
+      .unwrap();
+
+    assert_eq!(
+      format!("{:?}", tree),
+      "GenericTree (3 nodes, roots: [\"root\"])
+  \"a\" children parent=\"b\" children=[]
+  \"b\" left_right parent=\"root\" children=[\"a\"]
+  \"root\" children parent=\"root\" children=[\"b\"]"
+    );
+    assert_eq!(
+      format!("{:#?}", tree),
+      "GenericTree (3 nodes, roots: [\"root\"])
+  \"root\" children
+    \"b\" left_right
+      \"a\" children"
+    );
+    assert_eq!(
+      tree.get_node("b".to_string()).unwrap().to_string(),
+      "LeftRightNode(\"b\", L=\"a\" R=none)"
+    );
+    let node: Node<&str> = ChildrenNode::new("x", "x");
+    assert_eq!(node.to_string(), "ChildrenNode(\"x\", 0 children)");
   }
 }